@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# Builds the token programs the mollusk tests load from src/tests.
+#
+# The binaries are not checked in, each one is built from its published crate
+# with cargo build-sbf. Run from anywhere, needs the Solana toolchain.
+set -euo pipefail
+
+root="$(cd "$(dirname "${BASH_SOURCE[0]}")/.." && pwd)"
+out="$root/src/tests"
+work="$(mktemp -d)"
+trap 'rm -rf "$work"' EXIT
+
+# crate, version, name of the built .so
+programs=(
+    "spl-token 3.5.0 spl_token"
+    "spl-token-2022 8.0.1 spl_token_2022"
+)
+
+for program in "${programs[@]}"; do
+    read -r crate version lib <<<"$program"
+    target="$out/$lib-$version.so"
+    if [[ -f "$target" ]]; then
+        echo "$target already exists, skipping"
+        continue
+    fi
+
+    echo "building $crate $version"
+    curl -sSfL "https://crates.io/api/v1/crates/$crate/$version/download" \
+        | tar -xz -C "$work"
+    cargo build-sbf \
+        --manifest-path "$work/$crate-$version/Cargo.toml" \
+        --sbf-out-dir "$work/deploy"
+    cp "$work/deploy/$lib.so" "$target"
+done
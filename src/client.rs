@@ -0,0 +1,564 @@
+//! Off-chain instruction builders and account decoding.
+//!
+//! Each builder takes the accounts a caller actually has to pick and derives the
+//! program's PDAs (escrow, vault, config, delegation buffer and records) itself.
+
+use core::mem::offset_of;
+
+use pinocchio::program_error::ProgramError;
+use solana_instruction::{AccountMeta, Instruction};
+use solana_pubkey::Pubkey;
+
+use crate::{
+    instruction::{
+        commit::{MAGIC_CONTEXT, MAGIC_PROGRAM},
+        config::BPF_LOADER_UPGRADEABLE,
+        delegate::DELEGATION_ACCOUNT,
+        MyProgramInstrution,
+    },
+    state::{DataLen, Escrow},
+};
+
+pub const PROGRAM_ID: Pubkey = Pubkey::new_from_array(crate::ID);
+pub const DELEGATION_PROGRAM_ID: Pubkey = Pubkey::new_from_array(DELEGATION_ACCOUNT);
+pub const SYSTEM_PROGRAM_ID: Pubkey = Pubkey::new_from_array([0; 32]);
+pub const MAGIC_PROGRAM_ID: Pubkey = Pubkey::new_from_array(MAGIC_PROGRAM);
+pub const MAGIC_CONTEXT_ID: Pubkey = Pubkey::new_from_array(MAGIC_CONTEXT);
+pub const BPF_LOADER_UPGRADEABLE_ID: Pubkey = Pubkey::new_from_array(BPF_LOADER_UPGRADEABLE);
+
+/// A reasonable commit frequency for [`Delegate`], the program accepts
+/// anything from one second to one day.
+pub const DEFAULT_COMMIT_FREQUENCY_MS: u32 = 30_000;
+
+pub fn escrow_pda(maker: &Pubkey, seed: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"escrow", maker.as_ref(), &seed.to_le_bytes()],
+        &PROGRAM_ID,
+    )
+}
+
+/// Address of a maker's v1 escrow, from before escrows took a seed.
+pub fn escrow_v1_pda(maker: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"escrow", maker.as_ref()], &PROGRAM_ID)
+}
+
+pub fn vault_pda(escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault", escrow.as_ref()], &PROGRAM_ID)
+}
+
+pub fn config_pda() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"config"], &PROGRAM_ID)
+}
+
+/// The program's ProgramData account, it stores the upgrade authority.
+pub fn program_data_pda() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PROGRAM_ID.as_ref()], &BPF_LOADER_UPGRADEABLE_ID)
+}
+
+/// Holds the escrow's data while it is delegated.
+pub fn buffer_pda(escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"buffer", escrow.as_ref()], &PROGRAM_ID)
+}
+
+pub fn delegation_record_pda(escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"delegation", escrow.as_ref()], &DELEGATION_PROGRAM_ID)
+}
+
+pub fn delegation_metadata_pda(escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"delegation-metadata", escrow.as_ref()],
+        &DELEGATION_PROGRAM_ID,
+    )
+}
+
+/// Opens an offer of `amount_a` mint_a for `amount_b` mint_b.
+pub struct Make {
+    pub maker: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub maker_ata_a: Pubkey,
+    pub token_program: Pubkey,
+    pub seed: u64,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    // zero never expires
+    pub expiry: i64,
+    pub bounty: u64,
+    // None leaves the offer open to anyone
+    pub taker: Option<Pubkey>,
+}
+
+impl Make {
+    pub fn instruction(&self) -> Instruction {
+        let (escrow, bump) = escrow_pda(&self.maker, self.seed);
+        let (vault, _) = vault_pda(&escrow);
+
+        let mut data = vec![MyProgramInstrution::Make as u8, bump];
+        data.extend_from_slice(&self.seed.to_le_bytes());
+        data.extend_from_slice(&self.amount_a.to_le_bytes());
+        data.extend_from_slice(&self.amount_b.to_le_bytes());
+        data.extend_from_slice(&self.expiry.to_le_bytes());
+        data.extend_from_slice(&self.bounty.to_le_bytes());
+        data.extend_from_slice(self.taker.unwrap_or_default().as_ref());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new(self.maker, true),
+                AccountMeta::new_readonly(self.mint_a, false),
+                AccountMeta::new_readonly(self.mint_b, false),
+                AccountMeta::new(self.maker_ata_a, false),
+                AccountMeta::new(vault, false),
+                AccountMeta::new(escrow, false),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+                AccountMeta::new_readonly(self.token_program, false),
+            ],
+        )
+    }
+}
+
+/// Pays `amount` of mint_b into the maker's escrow, in full or in part.
+pub struct Take {
+    pub taker: Pubkey,
+    pub maker: Pubkey,
+    pub seed: u64,
+    pub mint_a: Pubkey,
+    // a Token-2022 transfer-fee mint, whose withheld fees are harvested into
+    // it when the vault closes, so it has to be writable
+    pub mint_a_transfer_fee: bool,
+    pub mint_b: Pubkey,
+    pub taker_ata_a: Pubkey,
+    pub taker_ata_b: Pubkey,
+    pub maker_ata_b: Pubkey,
+    pub token_program_a: Pubkey,
+    pub token_program_b: Pubkey,
+    // the config's fee recipient, or its mint_b token account. Only read when
+    // a fee is charged, fills are free until InitConfig has run
+    pub fee_recipient_b: Pubkey,
+    pub amount: u64,
+}
+
+impl Take {
+    pub fn instruction(&self) -> Instruction {
+        let (escrow, _) = escrow_pda(&self.maker, self.seed);
+        let (vault, _) = vault_pda(&escrow);
+        let (config, _) = config_pda();
+
+        let mut data = vec![MyProgramInstrution::Take as u8];
+        data.extend_from_slice(&self.amount.to_le_bytes());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new(self.taker, true),
+                AccountMeta::new(self.maker, false),
+                mint_a_meta(self.mint_a, self.mint_a_transfer_fee),
+                AccountMeta::new_readonly(self.mint_b, false),
+                AccountMeta::new(self.taker_ata_a, false),
+                AccountMeta::new(self.taker_ata_b, false),
+                AccountMeta::new(self.maker_ata_b, false),
+                AccountMeta::new(vault, false),
+                AccountMeta::new(escrow, false),
+                AccountMeta::new_readonly(self.token_program_a, false),
+                AccountMeta::new_readonly(self.token_program_b, false),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+                AccountMeta::new_readonly(config, false),
+                AccountMeta::new(self.fee_recipient_b, false),
+            ],
+        )
+    }
+}
+
+fn mint_a_meta(mint_a: Pubkey, transfer_fee: bool) -> AccountMeta {
+    if transfer_fee {
+        AccountMeta::new(mint_a, false)
+    } else {
+        AccountMeta::new_readonly(mint_a, false)
+    }
+}
+
+/// Closes an offer and returns the vault to the maker.
+pub struct Refund {
+    pub maker: Pubkey,
+    pub seed: u64,
+    pub mint_a: Pubkey,
+    // a Token-2022 transfer-fee mint, whose withheld fees are harvested into
+    // it when the vault closes, so it has to be writable
+    pub mint_a_transfer_fee: bool,
+    pub maker_ata_a: Pubkey,
+    pub token_program: Pubkey,
+    // refunds an expired offer on the maker's behalf, for the bounty
+    pub cranker: Option<Pubkey>,
+}
+
+impl Refund {
+    pub fn instruction(&self) -> Instruction {
+        let (escrow, _) = escrow_pda(&self.maker, self.seed);
+        let (vault, _) = vault_pda(&escrow);
+
+        let mut accounts = vec![
+            AccountMeta::new(self.maker, self.cranker.is_none()),
+            mint_a_meta(self.mint_a, self.mint_a_transfer_fee),
+            AccountMeta::new(self.maker_ata_a, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new(escrow, false),
+            AccountMeta::new_readonly(self.token_program, false),
+            AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+        ];
+        if let Some(cranker) = self.cranker {
+            accounts.push(AccountMeta::new(cranker, true));
+        }
+
+        Instruction::new_with_bytes(PROGRAM_ID, &[MyProgramInstrution::Refund as u8], accounts)
+    }
+}
+
+/// Reprices an open offer to `amount` mint_b and resizes its vault, topping it
+/// up and withdrawing from it in the same instruction.
+pub struct Amend {
+    pub maker: Pubkey,
+    pub seed: u64,
+    pub mint_a: Pubkey,
+    pub maker_ata_a: Pubkey,
+    pub token_program: Pubkey,
+    pub amount: u64,
+    pub top_up: u64,
+    pub withdraw: u64,
+}
+
+impl Amend {
+    pub fn instruction(&self) -> Instruction {
+        let (escrow, _) = escrow_pda(&self.maker, self.seed);
+        let (vault, _) = vault_pda(&escrow);
+
+        let mut data = vec![MyProgramInstrution::Amend as u8];
+        data.extend_from_slice(&self.amount.to_le_bytes());
+        data.extend_from_slice(&self.top_up.to_le_bytes());
+        data.extend_from_slice(&self.withdraw.to_le_bytes());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new(self.maker, true),
+                AccountMeta::new_readonly(self.mint_a, false),
+                AccountMeta::new(self.maker_ata_a, false),
+                AccountMeta::new(vault, false),
+                AccountMeta::new(escrow, false),
+                AccountMeta::new_readonly(self.token_program, false),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+            ],
+        )
+    }
+}
+
+/// Retires a v1 escrow, returning its deposit to the maker.
+pub struct Migrate {
+    pub maker: Pubkey,
+    pub mint_a: Pubkey,
+    pub maker_ata_a: Pubkey,
+    // the token account the v1 escrow was made with
+    pub vault: Pubkey,
+    pub token_program: Pubkey,
+}
+
+impl Migrate {
+    pub fn instruction(&self) -> Instruction {
+        let (escrow, _) = escrow_v1_pda(&self.maker);
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &[MyProgramInstrution::Migrate as u8],
+            vec![
+                AccountMeta::new(self.maker, true),
+                AccountMeta::new_readonly(self.mint_a, false),
+                AccountMeta::new(self.maker_ata_a, false),
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(escrow, false),
+                AccountMeta::new_readonly(self.token_program, false),
+            ],
+        )
+    }
+}
+
+/// Creates the program config. The admin has to be the program's upgrade
+/// authority.
+pub struct InitConfig {
+    pub admin: Pubkey,
+    pub fee_recipient: Pubkey,
+    pub fee_bps: u16,
+}
+
+impl InitConfig {
+    pub fn instruction(&self) -> Instruction {
+        let (config, _) = config_pda();
+        let (program_data, _) = program_data_pda();
+
+        let mut data = vec![MyProgramInstrution::InitConfig as u8];
+        data.extend_from_slice(self.fee_recipient.as_ref());
+        data.extend_from_slice(&self.fee_bps.to_le_bytes());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new(self.admin, true),
+                AccountMeta::new(config, false),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+                AccountMeta::new_readonly(program_data, false),
+            ],
+        )
+    }
+}
+
+/// Replaces the program config, signed by its current admin.
+pub struct UpdateConfig {
+    pub admin: Pubkey,
+    // the admin from now on, `admin` again to keep it
+    pub new_admin: Pubkey,
+    pub fee_recipient: Pubkey,
+    pub fee_bps: u16,
+}
+
+impl UpdateConfig {
+    pub fn instruction(&self) -> Instruction {
+        let (config, _) = config_pda();
+
+        let mut data = vec![MyProgramInstrution::UpdateConfig as u8];
+        data.extend_from_slice(self.new_admin.as_ref());
+        data.extend_from_slice(self.fee_recipient.as_ref());
+        data.extend_from_slice(&self.fee_bps.to_le_bytes());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new_readonly(self.admin, true),
+                AccountMeta::new(config, false),
+            ],
+        )
+    }
+}
+
+/// Seeds of a maker's escrow, bump excluded, for [`Delegate`].
+pub fn escrow_seeds(maker: &Pubkey, seed: u64) -> Vec<Vec<u8>> {
+    vec![
+        b"escrow".to_vec(),
+        maker.to_bytes().to_vec(),
+        seed.to_le_bytes().to_vec(),
+    ]
+}
+
+/// Seeds of the program config, for [`Delegate`] by the config admin.
+pub fn config_seeds() -> Vec<Vec<u8>> {
+    vec![b"config".to_vec()]
+}
+
+fn seeds_pda(seeds: &[Vec<u8>]) -> Pubkey {
+    let seeds: Vec<&[u8]> = seeds.iter().map(Vec::as_slice).collect();
+    Pubkey::find_program_address(&seeds, &PROGRAM_ID).0
+}
+
+/// Hands a PDA of the program over to the delegation program. The payer has
+/// to be one of the PDA's seeds, or the config admin for the config.
+pub struct Delegate {
+    pub payer: Pubkey,
+    // seeds of the delegated PDA, bump excluded
+    pub seeds: Vec<Vec<u8>>,
+    // how often the rollup commits the account back to the base layer
+    pub commit_frequency_ms: u32,
+    // None lets any validator pick up the account
+    pub validator: Option<Pubkey>,
+}
+
+impl Delegate {
+    pub fn instruction(&self) -> Instruction {
+        let pda = seeds_pda(&self.seeds);
+        let (buffer, _) = buffer_pda(&pda);
+        let (delegation_record, _) = delegation_record_pda(&pda);
+        let (delegation_metadata, _) = delegation_metadata_pda(&pda);
+
+        let mut data = vec![MyProgramInstrution::Delegate as u8];
+        data.extend_from_slice(&self.commit_frequency_ms.to_le_bytes());
+        data.extend_from_slice(self.validator.unwrap_or_default().as_ref());
+        data.extend_from_slice(&borsh::to_vec(&self.seeds).unwrap());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new(self.payer, true),
+                AccountMeta::new(pda, false),
+                AccountMeta::new_readonly(PROGRAM_ID, false),
+                AccountMeta::new(buffer, false),
+                AccountMeta::new(delegation_record, false),
+                AccountMeta::new_readonly(delegation_metadata, false),
+                AccountMeta::new_readonly(SYSTEM_PROGRAM_ID, false),
+            ],
+        )
+    }
+}
+
+/// Checkpoints a delegated PDA to the base layer. Sent to the rollup.
+pub struct Commit {
+    pub payer: Pubkey,
+    // seeds of the delegated PDA, bump excluded
+    pub seeds: Vec<Vec<u8>>,
+}
+
+impl Commit {
+    pub fn instruction(&self) -> Instruction {
+        let mut data = vec![MyProgramInstrution::Commit as u8];
+        data.extend_from_slice(&borsh::to_vec(&self.seeds).unwrap());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new(self.payer, true),
+                AccountMeta::new(seeds_pda(&self.seeds), false),
+                AccountMeta::new(MAGIC_CONTEXT_ID, false),
+                AccountMeta::new_readonly(MAGIC_PROGRAM_ID, false),
+            ],
+        )
+    }
+}
+
+/// Ends the delegation of a PDA. Sent to the rollup, the delegation program
+/// gives the PDA back on the base layer afterwards. The authority is the same
+/// as for [`Delegate`].
+pub struct CommitAndUndelegate {
+    pub authority: Pubkey,
+    // seeds of the delegated PDA, bump excluded
+    pub seeds: Vec<Vec<u8>>,
+}
+
+impl CommitAndUndelegate {
+    pub fn instruction(&self) -> Instruction {
+        let mut data = vec![MyProgramInstrution::CommitAndUndelegate as u8];
+        data.extend_from_slice(&borsh::to_vec(&self.seeds).unwrap());
+
+        Instruction::new_with_bytes(
+            PROGRAM_ID,
+            &data,
+            vec![
+                AccountMeta::new(self.authority, true),
+                AccountMeta::new(seeds_pda(&self.seeds), false),
+                AccountMeta::new(MAGIC_CONTEXT_ID, false),
+                AccountMeta::new_readonly(MAGIC_PROGRAM_ID, false),
+            ],
+        )
+    }
+}
+
+/// Size of an escrow account, usable as a `dataSize` filter.
+pub const ESCROW_LEN: usize = Escrow::LEN;
+
+// Offsets into the escrow account for `getProgramAccounts` memcmp filters.
+pub const DISCRIMINATOR_OFFSET: usize = offset_of!(Escrow, discriminator);
+pub const MAKER_OFFSET: usize = offset_of!(Escrow, maker);
+pub const MINT_A_OFFSET: usize = offset_of!(Escrow, mint_a);
+pub const MINT_B_OFFSET: usize = offset_of!(Escrow, mint_b);
+
+/// A memcmp filter, the bytes expected at `offset` of the account data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Memcmp {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl Memcmp {
+    /// Matches escrows of the current layout, other program accounts are skipped.
+    pub fn escrow() -> Self {
+        Self {
+            offset: DISCRIMINATOR_OFFSET,
+            bytes: vec![Escrow::DISCRIMINATOR, Escrow::VERSION],
+        }
+    }
+
+    pub fn maker(maker: &Pubkey) -> Self {
+        Self {
+            offset: MAKER_OFFSET,
+            bytes: maker.to_bytes().to_vec(),
+        }
+    }
+
+    pub fn mint_a(mint_a: &Pubkey) -> Self {
+        Self {
+            offset: MINT_A_OFFSET,
+            bytes: mint_a.to_bytes().to_vec(),
+        }
+    }
+
+    pub fn mint_b(mint_b: &Pubkey) -> Self {
+        Self {
+            offset: MINT_B_OFFSET,
+            bytes: mint_b.to_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EscrowState {
+    Open,
+    // can only be refunded now
+    Expired,
+}
+
+/// An escrow account decoded for display.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EscrowAccount {
+    pub maker: Pubkey,
+    pub seed: u64,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    // None for open offers
+    pub taker: Option<Pubkey>,
+    // mint_b still requested
+    pub amount: u64,
+    // mint_a left in the vault
+    pub deposit: u64,
+    // None if the offer never expires
+    pub expiry: Option<i64>,
+    pub bounty: u64,
+    pub native_a: bool,
+    pub native_b: bool,
+    pub bump: u8,
+}
+
+impl EscrowAccount {
+    /// Decodes the data of an escrow account of the current version.
+    pub fn decode(data: &[u8]) -> Result<Self, ProgramError> {
+        let escrow = Escrow::from_bytes(data)?;
+        let taker = Pubkey::new_from_array(escrow.taker);
+        let expiry = i64::from_le_bytes(escrow.expiry);
+
+        Ok(Self {
+            maker: Pubkey::new_from_array(escrow.maker),
+            seed: u64::from_le_bytes(escrow.seed),
+            mint_a: Pubkey::new_from_array(escrow.mint_a),
+            mint_b: Pubkey::new_from_array(escrow.mint_b),
+            taker: (taker != Pubkey::default()).then_some(taker),
+            amount: u64::from_le_bytes(escrow.amount),
+            deposit: u64::from_le_bytes(escrow.deposit),
+            expiry: (expiry != 0).then_some(expiry),
+            bounty: u64::from_le_bytes(escrow.bounty),
+            native_a: escrow.native_a != 0,
+            native_b: escrow.native_b != 0,
+            bump: escrow.bump,
+        })
+    }
+
+    pub fn state(&self, now: i64) -> EscrowState {
+        match self.expiry {
+            Some(expiry) if now >= expiry => EscrowState::Expired,
+            _ => EscrowState::Open,
+        }
+    }
+
+    /// Address of the escrow, derived from its maker and seed.
+    pub fn address(&self) -> Pubkey {
+        escrow_pda(&self.maker, self.seed).0
+    }
+}
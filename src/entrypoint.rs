@@ -12,23 +12,61 @@ no_allocator!();
 // Use the default panic handler.
 default_panic_handler!();
 
+type Processor = fn(&[AccountInfo], &[u8]) -> ProgramResult;
+
+// Indexed by discriminator, see `MyProgramInstrution::ALL`.
+const PROCESSORS: [(MyProgramInstrution, Processor); MyProgramInstrution::ALL.len()] = [
+    (MyProgramInstrution::Make, instruction::process_make),
+    (MyProgramInstrution::Take, instruction::process_take),
+    (MyProgramInstrution::Refund, instruction::process_refund),
+    (MyProgramInstrution::Delegate, instruction::process_delegate),
+    (MyProgramInstrution::UnDelegate, process_retired),
+    (
+        MyProgramInstrution::InitConfig,
+        instruction::process_init_config,
+    ),
+    (
+        MyProgramInstrution::UpdateConfig,
+        instruction::process_update_config,
+    ),
+    (MyProgramInstrution::Amend, instruction::process_amend),
+    (MyProgramInstrution::Migrate, instruction::process_migrate),
+    (MyProgramInstrution::Commit, instruction::process_commit),
+    (
+        MyProgramInstrution::CommitAndUndelegate,
+        instruction::process_commit_and_undelegate,
+    ),
+];
+
+// a processor registered under the wrong discriminator fails the build
+const _: () = {
+    let mut i = 0;
+    while i < PROCESSORS.len() {
+        assert!(PROCESSORS[i].0 as usize == i);
+        i += 1;
+    }
+};
+
+// retired discriminators stay assigned so they are never reused
+fn process_retired(_accounts: &[AccountInfo], _data: &[u8]) -> ProgramResult {
+    Err(ProgramError::InvalidInstructionData)
+}
+
 #[inline(always)]
 fn process_instruction(
     _program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
+    //the delegation program calls back with its own 8-byte discriminator
+    if let Some(data) = instruction_data.strip_prefix(&instruction::UNDELEGATE_DISCRIMINATOR) {
+        return instruction::process_undelegate_callback(accounts, data);
+    }
+
     let (ix_disc, instruction_data) = instruction_data
         .split_first()
         .ok_or(ProgramError::InvalidInstructionData)?;
 
-    match MyProgramInstrution::try_from(ix_disc)? {
-        MyProgramInstrution::Make => instruction::process_make(accounts, instruction_data),
-        MyProgramInstrution::Take => instruction::process_take(accounts),
-        MyProgramInstrution::Refund => instruction::process_refund(accounts),
-        MyProgramInstrution::Delegate => instruction::process_delegate(accounts, instruction_data),
-        MyProgramInstrution::UnDelegate => {
-            instruction::process_undelegate(accounts, instruction_data)
-        }
-    }
+    let (_, process) = PROCESSORS[MyProgramInstrution::try_from(ix_disc)? as usize];
+    process(accounts, instruction_data)
 }
@@ -1,24 +1,180 @@
 use pinocchio::program_error::ProgramError;
+use pinocchio_log::log;
 
-#[derive(Clone, PartialEq)]
+/// Program errors, returned as `ProgramError::Custom(code)`.
+///
+/// Codes are part of the program's interface: never reorder or reuse them,
+/// only append new variants.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MyProgramError {
     // overflow error
-    WriteOverflow,
+    WriteOverflow = 0,
     // invalid instruction data
-    InvalidInstructionData,
+    InvalidInstructionData = 1,
     // pda mismatch
-    PdaMismatch,
+    PdaMismatch = 2,
     // Invalid Owner
-    InvalidOwner,
+    InvalidOwner = 3,
     // Not a system account
-    InvalidAccount,
+    InvalidAccount = 4,
     //Unable to Deserialize
-    DeserializationFailed,
-    FailedRealloc,
+    DeserializationFailed = 5,
+    // failed to resize an account
+    FailedRealloc = 6,
+    // fill amount is zero, exceeds the remaining amount or releases nothing
+    InvalidFillAmount = 7,
+    // expiry is set in the past
+    InvalidExpiry = 8,
+    // offer can no longer be taken
+    EscrowExpired = 9,
+    // only the maker can refund before expiry
+    EscrowNotExpired = 10,
+    // offer is reserved for another taker
+    InvalidTaker = 11,
+    // not the SPL Token or Token-2022 program owning the mint
+    InvalidTokenProgram = 12,
+    // Token-2022 mint cannot be transferred at all
+    NonTransferableMint = 13,
+    // Token-2022 mint lets a third party move the vault's tokens
+    PermanentDelegateMint = 14,
+    // Token-2022 mint extension the escrow cannot honour
+    UnsupportedMintExtension = 15,
+    // protocol fee above Config::MAX_FEE_BPS
+    InvalidFeeBps = 16,
+    // signer is not the config admin
+    InvalidAdmin = 17,
+    // fee account does not belong to the configured fee recipient
+    InvalidFeeRecipient = 18,
+    // escrow is delegated to the ephemeral rollup
+    EscrowDelegated = 19,
+    // amount is zero or would empty the vault
+    InvalidAmount = 20,
+    // account is not of the type expected
+    InvalidDiscriminator = 21,
+    // escrow layout is outdated, run Migrate first
+    UnsupportedVersion = 22,
+    // escrow is not the PDA of its maker and seed
+    EscrowPdaMismatch = 23,
+    // mint does not match the one recorded in the escrow
+    MintMismatch = 24,
+    // vault is not held by its escrow
+    VaultOwnerMismatch = 25,
+    // a required signature is missing
+    NotSigner = 26,
+    // account must be writable
+    NotWritable = 27,
+    // not the program the instruction expects
+    InvalidProgram = 28,
+    // commit frequency is outside the accepted range
+    InvalidCommitFrequency = 29,
+    // signer is not one of the delegated account's seeds
+    InvalidDelegationAuthority = 30,
+}
+
+impl MyProgramError {
+    pub const ALL: [MyProgramError; 31] = [
+        MyProgramError::WriteOverflow,
+        MyProgramError::InvalidInstructionData,
+        MyProgramError::PdaMismatch,
+        MyProgramError::InvalidOwner,
+        MyProgramError::InvalidAccount,
+        MyProgramError::DeserializationFailed,
+        MyProgramError::FailedRealloc,
+        MyProgramError::InvalidFillAmount,
+        MyProgramError::InvalidExpiry,
+        MyProgramError::EscrowExpired,
+        MyProgramError::EscrowNotExpired,
+        MyProgramError::InvalidTaker,
+        MyProgramError::InvalidTokenProgram,
+        MyProgramError::NonTransferableMint,
+        MyProgramError::PermanentDelegateMint,
+        MyProgramError::UnsupportedMintExtension,
+        MyProgramError::InvalidFeeBps,
+        MyProgramError::InvalidAdmin,
+        MyProgramError::InvalidFeeRecipient,
+        MyProgramError::EscrowDelegated,
+        MyProgramError::InvalidAmount,
+        MyProgramError::InvalidDiscriminator,
+        MyProgramError::UnsupportedVersion,
+        MyProgramError::EscrowPdaMismatch,
+        MyProgramError::MintMismatch,
+        MyProgramError::VaultOwnerMismatch,
+        MyProgramError::NotSigner,
+        MyProgramError::NotWritable,
+        MyProgramError::InvalidProgram,
+        MyProgramError::InvalidCommitFrequency,
+        MyProgramError::InvalidDelegationAuthority,
+    ];
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            MyProgramError::WriteOverflow => "write overflows the account data",
+            MyProgramError::InvalidInstructionData => "invalid instruction data",
+            MyProgramError::PdaMismatch => "account is not the expected PDA",
+            MyProgramError::InvalidOwner => "account has the wrong owner",
+            MyProgramError::InvalidAccount => "account does not match the escrow",
+            MyProgramError::DeserializationFailed => "failed to deserialize account data",
+            MyProgramError::FailedRealloc => "failed to resize account",
+            MyProgramError::InvalidFillAmount => {
+                "fill amount is zero, too large or releases nothing"
+            }
+            MyProgramError::InvalidExpiry => "expiry is in the past",
+            MyProgramError::EscrowExpired => "escrow has expired",
+            MyProgramError::EscrowNotExpired => "only the maker can refund before expiry",
+            MyProgramError::InvalidTaker => "offer is reserved for another taker",
+            MyProgramError::InvalidTokenProgram => "token program does not own the mint",
+            MyProgramError::NonTransferableMint => "mint is non-transferable",
+            MyProgramError::PermanentDelegateMint => "mint has a permanent delegate",
+            MyProgramError::UnsupportedMintExtension => "mint extension is not supported",
+            MyProgramError::InvalidFeeBps => "protocol fee is above the maximum",
+            MyProgramError::InvalidAdmin => "signer is not the config admin",
+            MyProgramError::InvalidFeeRecipient => {
+                "fee account does not belong to the fee recipient"
+            }
+            MyProgramError::EscrowDelegated => "escrow is delegated",
+            MyProgramError::InvalidAmount => "amount is zero or would empty the vault",
+            MyProgramError::InvalidDiscriminator => "account is not of the expected type",
+            MyProgramError::UnsupportedVersion => "escrow layout is outdated, migrate it first",
+            MyProgramError::EscrowPdaMismatch => "escrow is not the PDA of its maker and seed",
+            MyProgramError::MintMismatch => "mint does not match the escrow",
+            MyProgramError::VaultOwnerMismatch => "vault is not held by the escrow",
+            MyProgramError::NotSigner => "missing required signature",
+            MyProgramError::NotWritable => "account is not writable",
+            MyProgramError::InvalidProgram => "unexpected program account",
+            MyProgramError::InvalidCommitFrequency => "commit frequency is out of range",
+            MyProgramError::InvalidDelegationAuthority => {
+                "signer may not delegate or undelegate this account"
+            }
+        }
+    }
 }
 
 impl From<MyProgramError> for ProgramError {
     fn from(e: MyProgramError) -> Self {
+        log!("Error: {}", e.message());
         Self::Custom(e as u32)
     }
 }
+
+#[cfg(feature = "std")]
+impl TryFrom<u32> for MyProgramError {
+    type Error = u32;
+
+    fn try_from(code: u32) -> Result<Self, Self::Error> {
+        MyProgramError::ALL
+            .into_iter()
+            .find(|e| *e as u32 == code)
+            .ok_or(code)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Display for MyProgramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (error {})", self.message(), *self as u32)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MyProgramError {}
@@ -0,0 +1,76 @@
+//! Off-chain decoding of the events logged by the program.
+
+use std::fmt;
+
+use super::{
+    AmendEvent, DelegateEvent, Event, EventKind, MakeEvent, MigrateEvent, RefundEvent, TakeEvent,
+    UndelegateEvent, EVENT_VERSION,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EscrowEvent {
+    Make(MakeEvent),
+    Take(TakeEvent),
+    Refund(RefundEvent),
+    Delegate(DelegateEvent),
+    Undelegate(UndelegateEvent),
+    Amend(AmendEvent),
+    Migrate(MigrateEvent),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    // not a header and a body
+    InvalidFields,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    // body doesn't match the size of its event
+    InvalidLength,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidFields => write!(f, "expected an event header and body"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported event version {version}")
+            }
+            DecodeError::UnknownKind(kind) => write!(f, "unknown event kind {kind}"),
+            DecodeError::InvalidLength => write!(f, "event body has the wrong length"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl EscrowEvent {
+    /// Decodes the fields of one `Program data:` log line, already base64 decoded.
+    pub fn decode(fields: &[&[u8]]) -> Result<Self, DecodeError> {
+        let [header, body] = fields else {
+            return Err(DecodeError::InvalidFields);
+        };
+        let [version, kind] = header[..] else {
+            return Err(DecodeError::InvalidFields);
+        };
+        if version != EVENT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        match EventKind::try_from(kind).map_err(DecodeError::UnknownKind)? {
+            EventKind::Make => read(body).map(EscrowEvent::Make),
+            EventKind::Take => read(body).map(EscrowEvent::Take),
+            EventKind::Refund => read(body).map(EscrowEvent::Refund),
+            EventKind::Delegate => read(body).map(EscrowEvent::Delegate),
+            EventKind::Undelegate => read(body).map(EscrowEvent::Undelegate),
+            EventKind::Amend => read(body).map(EscrowEvent::Amend),
+            EventKind::Migrate => read(body).map(EscrowEvent::Migrate),
+        }
+    }
+}
+
+fn read<T: Event>(body: &[u8]) -> Result<T, DecodeError> {
+    if body.len() != core::mem::size_of::<T>() {
+        return Err(DecodeError::InvalidLength);
+    }
+    Ok(bytemuck::pod_read_unaligned(body))
+}
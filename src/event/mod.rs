@@ -0,0 +1,171 @@
+//! Binary events logged with `sol_log_data` so indexers don't have to work
+//! out what happened from token balance changes.
+//!
+//! Every event is logged as two fields: the header `[EVENT_VERSION, kind]`
+//! followed by the raw bytes of the event struct. The `std` feature adds a
+//! [`decode`] module turning them back into an [`EscrowEvent`](decode::EscrowEvent).
+
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{log::sol_log_data, pubkey::Pubkey};
+
+#[cfg(feature = "std")]
+pub mod decode;
+
+/// Bumped whenever an event layout changes.
+pub const EVENT_VERSION: u8 = 1;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Make,
+    Take,
+    Refund,
+    Delegate,
+    Undelegate,
+    Amend,
+    Migrate,
+}
+
+impl TryFrom<u8> for EventKind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(EventKind::Make),
+            1 => Ok(EventKind::Take),
+            2 => Ok(EventKind::Refund),
+            3 => Ok(EventKind::Delegate),
+            4 => Ok(EventKind::Undelegate),
+            5 => Ok(EventKind::Amend),
+            6 => Ok(EventKind::Migrate),
+            _ => Err(value),
+        }
+    }
+}
+
+pub trait Event: Pod {
+    const KIND: EventKind;
+
+    fn emit(&self) {
+        sol_log_data(&[&[EVENT_VERSION, Self::KIND as u8], bytemuck::bytes_of(self)]);
+    }
+}
+
+/// An offer was opened and its vault funded.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Zeroable, Pod)]
+pub struct MakeEvent {
+    pub escrow: Pubkey,
+    pub maker: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    // all zeroes for open offers
+    pub taker: Pubkey,
+    // mint_a that landed in the vault
+    pub deposit: [u8; 8],
+    // mint_b requested for the whole deposit
+    pub amount: [u8; 8],
+    pub expiry: [u8; 8],
+    pub bounty: [u8; 8],
+}
+
+impl Event for MakeEvent {
+    const KIND: EventKind = EventKind::Make;
+}
+
+/// An offer was filled, in full or in part.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Zeroable, Pod)]
+pub struct TakeEvent {
+    pub escrow: Pubkey,
+    pub taker: Pubkey,
+    // mint_b paid by the taker, protocol fee included
+    pub paid: [u8; 8],
+    // share of `paid` sent to the fee recipient
+    pub fee: [u8; 8],
+    // mint_a released to the taker
+    pub released: [u8; 8],
+    // what is left of the offer, both zero once it is filled
+    pub remaining_a: [u8; 8],
+    pub remaining_b: [u8; 8],
+}
+
+impl Event for TakeEvent {
+    const KIND: EventKind = EventKind::Take;
+}
+
+/// An offer was closed and its vault returned to the maker.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Zeroable, Pod)]
+pub struct RefundEvent {
+    pub escrow: Pubkey,
+    pub maker: Pubkey,
+    // mint_a returned to the maker
+    pub refunded: [u8; 8],
+    // all zeroes when the maker refunded it themselves
+    pub cranker: Pubkey,
+    // lamports paid to the cranker
+    pub bounty: [u8; 8],
+}
+
+impl Event for RefundEvent {
+    const KIND: EventKind = EventKind::Refund;
+}
+
+/// A PDA of the program was handed over to the delegation program.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Zeroable, Pod)]
+pub struct DelegateEvent {
+    // the delegated PDA, an escrow or the config
+    pub account: Pubkey,
+    // the seed signer or config admin who delegated it
+    pub authority: Pubkey,
+}
+
+impl Event for DelegateEvent {
+    const KIND: EventKind = EventKind::Delegate;
+}
+
+/// An escrow was given back to the program by the delegation program.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Zeroable, Pod)]
+pub struct UndelegateEvent {
+    pub escrow: Pubkey,
+    pub maker: Pubkey,
+}
+
+impl Event for UndelegateEvent {
+    const KIND: EventKind = EventKind::Undelegate;
+}
+
+/// An open offer was repriced or its vault resized by the maker.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Zeroable, Pod)]
+pub struct AmendEvent {
+    pub escrow: Pubkey,
+    pub maker: Pubkey,
+    // mint_a that landed in the vault from the top up
+    pub received: [u8; 8],
+    // mint_a returned to the maker
+    pub withdrawn: [u8; 8],
+    // the amended offer
+    pub deposit: [u8; 8],
+    pub amount: [u8; 8],
+}
+
+impl Event for AmendEvent {
+    const KIND: EventKind = EventKind::Amend;
+}
+
+/// A v1 escrow was retired and its deposit returned to the maker.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Zeroable, Pod)]
+pub struct MigrateEvent {
+    pub escrow: Pubkey,
+    pub maker: Pubkey,
+    pub refunded: [u8; 8],
+}
+
+impl Event for MigrateEvent {
+    const KIND: EventKind = EventKind::Migrate;
+}
@@ -0,0 +1,169 @@
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo,
+    instruction::{Seed, Signer},
+    program_error::ProgramError,
+    ProgramResult,
+};
+
+use crate::{
+    error::MyProgramError,
+    event::{AmendEvent, Event},
+    state::{load_ix_data, DataLen, Escrow},
+    token::{
+        check_token_program, check_vault_owner, mint_decimals, token_account_amount,
+        TransferChecked,
+    },
+    validation::{check_escrow_owner, AccountCheck},
+};
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+pub struct AmendEscrowIx {
+    // new amount of mint_b requested for what is left in the vault
+    pub amount: [u8; 8],
+    // mint_a added to the vault
+    pub top_up: [u8; 8],
+    // mint_a returned to the maker
+    pub withdraw: [u8; 8],
+}
+
+impl DataLen for AmendEscrowIx {
+    const LEN: usize = core::mem::size_of::<AmendEscrowIx>();
+}
+
+/// Lets the maker reprice an open offer and resize its vault in place.
+pub fn process_amend(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [maker, mint_a, maker_ata_a, vault, escrow, token_program, system_program] = accounts
+    else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+
+    maker.check_signer()?;
+    maker.check_writable()?;
+    vault.check_writable()?;
+    escrow.check_writable()?;
+    system_program.check_program(&pinocchio_system::ID)?;
+    check_escrow_owner(escrow)?;
+
+    let ix_data = load_ix_data::<AmendEscrowIx>(data)?;
+    let amount = u64::from_le_bytes(ix_data.amount);
+    let top_up = u64::from_le_bytes(ix_data.top_up);
+    let withdraw = u64::from_le_bytes(ix_data.withdraw);
+
+    if amount == 0 {
+        return Err(MyProgramError::InvalidAmount.into());
+    }
+
+    let escrow_account = Escrow::load(escrow)?;
+
+    if escrow_account.maker != *maker.key() {
+        return Err(MyProgramError::InvalidAccount.into());
+    }
+    if escrow_account.mint_a != *mint_a.key() {
+        return Err(MyProgramError::MintMismatch.into());
+    }
+
+    escrow.check_pda(
+        &[
+            b"escrow",
+            maker.key(),
+            &escrow_account.seed,
+            &[escrow_account.bump],
+        ],
+        MyProgramError::EscrowPdaMismatch,
+    )?;
+
+    let native_a = escrow_account.native_a != 0;
+    vault.find_pda(&[b"vault", escrow.key()], MyProgramError::PdaMismatch)?;
+    check_vault_owner(vault, escrow, native_a)?;
+    if !native_a {
+        maker_ata_a.check_writable()?;
+        check_token_program(mint_a, token_program)?;
+    }
+
+    let deposit = u64::from_le_bytes(escrow_account.deposit);
+    let escrow_seed = escrow_account.seed;
+    let bump = [escrow_account.bump];
+    drop(escrow_account);
+
+    //top up first so a maker can swap part of the vault in one go
+    let received = if top_up == 0 {
+        0
+    } else if native_a {
+        pinocchio_system::instructions::Transfer {
+            from: maker,
+            to: vault,
+            lamports: top_up,
+        }
+        .invoke()?;
+        top_up
+    } else {
+        let vault_before = token_account_amount(vault)?;
+
+        TransferChecked {
+            from: maker_ata_a,
+            mint: mint_a,
+            to: vault,
+            authority: maker,
+            amount: top_up,
+            decimals: mint_decimals(mint_a)?,
+            token_program: token_program.key(),
+        }
+        .invoke()?;
+
+        //transfer fees are withheld from what lands in the vault
+        token_account_amount(vault)? - vault_before
+    };
+
+    //an emptied vault is a refund, not an amendment
+    let deposit = deposit
+        .checked_add(received)
+        .and_then(|deposit| deposit.checked_sub(withdraw))
+        .filter(|deposit| *deposit != 0)
+        .ok_or(MyProgramError::InvalidAmount)?;
+
+    if withdraw != 0 {
+        if native_a {
+            unsafe {
+                *vault.borrow_mut_lamports_unchecked() -= withdraw;
+                *maker.borrow_mut_lamports_unchecked() += withdraw;
+            }
+        } else {
+            let seed = [
+                Seed::from(b"escrow"),
+                Seed::from(maker.key()),
+                Seed::from(&escrow_seed),
+                Seed::from(&bump),
+            ];
+
+            TransferChecked {
+                from: vault,
+                mint: mint_a,
+                to: maker_ata_a,
+                authority: escrow,
+                amount: withdraw,
+                decimals: mint_decimals(mint_a)?,
+                token_program: token_program.key(),
+            }
+            .invoke_signed(&[Signer::from(&seed)])?;
+        }
+    }
+
+    let mut escrow_account = Escrow::load_mut(escrow)?;
+    escrow_account.amount = amount.to_le_bytes();
+    escrow_account.deposit = deposit.to_le_bytes();
+    drop(escrow_account);
+
+    AmendEvent {
+        escrow: *escrow.key(),
+        maker: *maker.key(),
+        received: received.to_le_bytes(),
+        withdrawn: withdraw.to_le_bytes(),
+        deposit: deposit.to_le_bytes(),
+        amount: amount.to_le_bytes(),
+    }
+    .emit();
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use pinocchio::{
+    account_info::AccountInfo,
+    cpi::invoke,
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    ProgramResult,
+};
+
+use crate::{
+    error::MyProgramError,
+    instruction::PdaSeeds,
+    validation::{check_escrow_owner, AccountCheck},
+};
+
+pub const MAGIC_PROGRAM: Pubkey =
+    pinocchio_pubkey::pubkey!("Magic11111111111111111111111111111111111111");
+pub const MAGIC_CONTEXT: Pubkey =
+    pinocchio_pubkey::pubkey!("MagicContext1111111111111111111111111111111");
+
+// the magic program's instructions, a little-endian u32 variant index
+const SCHEDULE_COMMIT: [u8; 4] = [1, 0, 0, 0];
+const SCHEDULE_COMMIT_AND_UNDELEGATE: [u8; 4] = [2, 0, 0, 0];
+
+/// Checkpoints a delegated PDA to the base layer, the PDA stays delegated and
+/// keeps running on the rollup.
+///
+/// Sent to the rollup, where the PDA is still held by this program. Anyone can
+/// schedule a commit, the payer only covers it.
+pub fn process_commit(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [payer, pda_acc, magic_context, magic_program] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+
+    check_commit_accounts(payer, pda_acc, magic_context, magic_program)?;
+    parse_pda_seeds(pda_acc, data)?;
+
+    schedule(payer, pda_acc, magic_context, &SCHEDULE_COMMIT)
+}
+
+/// Commits a delegated PDA one last time and hands it back to the base layer,
+/// where the delegation program then calls back into this program.
+///
+/// Sent to the rollup by whoever may delegate the PDA: one of its seeds, or the
+/// config admin for the config.
+pub fn process_commit_and_undelegate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [authority, pda_acc, magic_context, magic_program] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+
+    check_commit_accounts(authority, pda_acc, magic_context, magic_program)?;
+    parse_pda_seeds(pda_acc, data)?.check_authority(authority, pda_acc)?;
+
+    schedule(
+        authority,
+        pda_acc,
+        magic_context,
+        &SCHEDULE_COMMIT_AND_UNDELEGATE,
+    )
+}
+
+//the instruction data is the PDA's seeds, they have to derive the account
+fn parse_pda_seeds<'a>(
+    pda_acc: &AccountInfo,
+    data: &'a [u8],
+) -> Result<PdaSeeds<'a>, ProgramError> {
+    let (pda_seeds, rest) = PdaSeeds::parse(data)?;
+    if !rest.is_empty() {
+        return Err(MyProgramError::InvalidInstructionData.into());
+    }
+    pda_acc.find_pda(pda_seeds.as_slice(), MyProgramError::PdaMismatch)?;
+    Ok(pda_seeds)
+}
+
+fn check_commit_accounts(
+    payer: &AccountInfo,
+    pda_acc: &AccountInfo,
+    magic_context: &AccountInfo,
+    magic_program: &AccountInfo,
+) -> ProgramResult {
+    payer.check_signer()?;
+    payer.check_writable()?;
+    pda_acc.check_writable()?;
+    check_escrow_owner(pda_acc)?;
+    magic_context.check_writable()?;
+    if magic_context.key() != &MAGIC_CONTEXT {
+        return Err(MyProgramError::InvalidAccount.into());
+    }
+    magic_program.check_program(&MAGIC_PROGRAM)
+}
+
+fn schedule(
+    payer: &AccountInfo,
+    pda_acc: &AccountInfo,
+    magic_context: &AccountInfo,
+    data: &[u8],
+) -> ProgramResult {
+    let account_metas = [
+        AccountMeta::new(payer.key(), true, true),
+        AccountMeta::new(magic_context.key(), true, false),
+        AccountMeta::new(pda_acc.key(), true, false),
+    ];
+
+    let instruction = Instruction {
+        program_id: &MAGIC_PROGRAM,
+        accounts: &account_metas,
+        data,
+    };
+
+    invoke(&instruction, &[payer, magic_context, pda_acc])
+}
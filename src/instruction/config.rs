@@ -0,0 +1,151 @@
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{
+    account_info::AccountInfo,
+    instruction::{Seed, Signer},
+    program_error::ProgramError,
+    pubkey::{find_program_address, Pubkey},
+    sysvars::{rent::Rent, Sysvar},
+    ProgramResult,
+};
+
+use crate::{
+    error::MyProgramError,
+    instruction::create_pda_account,
+    state::{load_acc_mut, load_ix_data, Config, DataLen},
+    validation::AccountCheck,
+};
+
+pub const BPF_LOADER_UPGRADEABLE: Pubkey =
+    pinocchio_pubkey::pubkey!("BPFLoaderUpgradeab1e11111111111111111111111");
+
+// ProgramData is a u32 tag, the u64 deploy slot, then an optional authority
+const PROGRAM_DATA_TAG: [u8; 4] = [3, 0, 0, 0];
+const PROGRAM_DATA_AUTHORITY_OFFSET: usize = 12;
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+pub struct InitConfigIx {
+    pub fee_recipient: Pubkey,
+    pub fee_bps: [u8; 2],
+}
+
+impl DataLen for InitConfigIx {
+    const LEN: usize = core::mem::size_of::<InitConfigIx>();
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+pub struct UpdateConfigIx {
+    pub admin: Pubkey,
+    pub fee_recipient: Pubkey,
+    pub fee_bps: [u8; 2],
+}
+
+impl DataLen for UpdateConfigIx {
+    const LEN: usize = core::mem::size_of::<UpdateConfigIx>();
+}
+
+fn check_fee_bps(fee_bps: [u8; 2]) -> ProgramResult {
+    if u16::from_le_bytes(fee_bps) > Config::MAX_FEE_BPS {
+        return Err(MyProgramError::InvalidFeeBps.into());
+    }
+    Ok(())
+}
+
+/// Checks that `authority` is the upgrade authority stored in this program's
+/// ProgramData account.
+fn check_upgrade_authority(program_data: &AccountInfo, authority: &AccountInfo) -> ProgramResult {
+    let (address, _) = find_program_address(&[crate::ID.as_ref()], &BPF_LOADER_UPGRADEABLE);
+    if program_data.key() != &address {
+        return Err(MyProgramError::PdaMismatch.into());
+    }
+    program_data.check_owner(&BPF_LOADER_UPGRADEABLE)?;
+
+    let data = program_data.try_borrow_data()?;
+    if data.len() < PROGRAM_DATA_AUTHORITY_OFFSET + 33 || data[..4] != PROGRAM_DATA_TAG {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    //an immutable program has no authority left to create the config
+    let upgrade_authority =
+        &data[PROGRAM_DATA_AUTHORITY_OFFSET..PROGRAM_DATA_AUTHORITY_OFFSET + 33];
+    if upgrade_authority[0] != 1 || upgrade_authority[1..] != authority.key()[..] {
+        return Err(MyProgramError::InvalidAdmin.into());
+    }
+    Ok(())
+}
+
+/// Creates the program config, the signer becomes its admin. Only the
+/// program's upgrade authority can create it.
+pub fn process_init_config(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [admin, config, system_program, program_data] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+
+    admin.check_signer()?;
+    admin.check_writable()?;
+    config.check_writable()?;
+    system_program.check_program(&pinocchio_system::ID)?;
+    check_upgrade_authority(program_data, admin)?;
+
+    let ix_data = load_ix_data::<InitConfigIx>(data)?;
+    check_fee_bps(ix_data.fee_bps)?;
+
+    let bump = config.find_pda(&[Config::SEED], MyProgramError::PdaMismatch)?;
+
+    if unsafe { config.owner() } == &crate::ID {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let bump_seed = [bump];
+    let seed = [Seed::from(Config::SEED), Seed::from(&bump_seed)];
+
+    create_pda_account(
+        admin,
+        config,
+        Rent::get()?.minimum_balance(Config::LEN),
+        Config::LEN,
+        &crate::ID,
+        Signer::from(&seed),
+    )?;
+
+    Config::initialize(
+        config,
+        Config {
+            discriminator: Config::DISCRIMINATOR,
+            admin: *admin.key(),
+            fee_recipient: ix_data.fee_recipient,
+            fee_bps: ix_data.fee_bps,
+            bump,
+        },
+    );
+
+    Ok(())
+}
+
+/// Replaces the admin, fee recipient and fee of the program config.
+pub fn process_update_config(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [admin, config] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+
+    admin.check_signer()?;
+    config.check_writable()?;
+    config.check_owner(&crate::ID)?;
+
+    let ix_data = load_ix_data::<UpdateConfigIx>(data)?;
+    check_fee_bps(ix_data.fee_bps)?;
+
+    let mut config_data = config.try_borrow_mut_data()?;
+    let config_account = load_acc_mut::<Config>(&mut config_data)?;
+
+    if config_account.admin != *admin.key() {
+        return Err(MyProgramError::InvalidAdmin.into());
+    }
+
+    config_account.admin = ix_data.admin;
+    config_account.fee_recipient = ix_data.fee_recipient;
+    config_account.fee_bps = ix_data.fee_bps;
+
+    Ok(())
+}
@@ -1,19 +1,20 @@
 use crate::{
     error::MyProgramError,
-    state::{DataLen, Escrow},
+    event::{DelegateEvent, Event},
+    state::{load_acc, load_ix_data, Config, DataLen},
+    validation::AccountCheck,
 };
 use borsh::{to_vec, BorshDeserialize, BorshSerialize};
-use bytemuck::{from_bytes, Pod, Zeroable};
+use bytemuck::{Pod, Zeroable};
 use pinocchio::{
     account_info::AccountInfo,
     cpi::invoke_signed,
     instruction::{AccountMeta, Instruction, Seed, Signer},
     program_error::ProgramError,
-    pubkey::{self, Pubkey},
+    pubkey::{Pubkey, MAX_SEEDS, MAX_SEED_LEN},
     sysvars::{rent::Rent, Sysvar},
     ProgramResult,
 };
-use pinocchio_log::log;
 #[derive(Debug, BorshSerialize, BorshDeserialize)]
 pub struct DelegateAccountArgs {
     pub commit_frequency_ms: u32,
@@ -34,33 +35,145 @@ impl Default for DelegateAccountArgs {
 pub const DELEGATION_ACCOUNT: Pubkey =
     pinocchio_pubkey::pubkey!("DELeGGvXpWV2fqJUhqcF5ZSYMS4JTLjteaAMARRSaeSh");
 
+/// Commit frequencies the rollup is allowed to run a delegated account at.
+pub const MIN_COMMIT_FREQUENCY_MS: u32 = 1_000;
+pub const MAX_COMMIT_FREQUENCY_MS: u32 = 24 * 60 * 60 * 1_000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+pub struct DelegateIx {
+    // how often the rollup commits the account back to the base layer
+    pub commit_frequency_ms: [u8; 4],
+    // all zeroes lets any validator pick up the account
+    pub validator: Pubkey,
+}
+
+impl DataLen for DelegateIx {
+    const LEN: usize = core::mem::size_of::<DelegateIx>();
+}
+
+/// Seeds of a delegated PDA, bump excluded, read from instruction data laid
+/// out like a borsh `Vec<Vec<u8>>`. One seed slot is left for the bump.
+pub struct PdaSeeds<'a> {
+    seeds: [&'a [u8]; MAX_SEEDS - 1],
+    len: usize,
+}
+
+impl<'a> PdaSeeds<'a> {
+    /// Parses the seeds at the start of `data` and returns the bytes after them.
+    pub fn parse(data: &'a [u8]) -> Result<(Self, &'a [u8]), ProgramError> {
+        let (count, mut data) = read_u32(data)?;
+        let mut seeds = Self {
+            seeds: [&[][..]; MAX_SEEDS - 1],
+            len: count as usize,
+        };
+        if seeds.len > seeds.seeds.len() {
+            return Err(MyProgramError::InvalidInstructionData.into());
+        }
+
+        for seed in &mut seeds.seeds[..seeds.len] {
+            let (len, rest) = read_u32(data)?;
+            let len = len as usize;
+            if len > MAX_SEED_LEN || len > rest.len() {
+                return Err(MyProgramError::InvalidInstructionData.into());
+            }
+            (*seed, data) = rest.split_at(len);
+        }
+
+        Ok((seeds, data))
+    }
+
+    pub fn as_slice(&self) -> &[&'a [u8]] {
+        &self.seeds[..self.len]
+    }
+
+    /// Checks `authority` may move `pda` in and out of delegation: one of the
+    /// seeds, or for the program config, which has no signer seed, its admin.
+    pub fn check_authority(&self, authority: &AccountInfo, pda: &AccountInfo) -> ProgramResult {
+        authority.check_signer()?;
+        if self.as_slice().contains(&authority.key().as_ref()) {
+            return Ok(());
+        }
+        if self.as_slice() == [Config::SEED] {
+            let data = pda.try_borrow_data()?;
+            if load_acc::<Config>(&data)?.admin == *authority.key() {
+                return Ok(());
+            }
+        }
+        Err(MyProgramError::InvalidDelegationAuthority.into())
+    }
+
+    /// Signer seeds with `bump` appended, only the first `as_slice().len() + 1`
+    /// are meaningful.
+    pub fn signer_seeds<'b>(&'b self, bump: &'b [u8; 1]) -> [Seed<'b>; MAX_SEEDS] {
+        core::array::from_fn(|i| match self.as_slice().get(i) {
+            Some(seed) => Seed::from(*seed),
+            None => Seed::from(bump.as_slice()),
+        })
+    }
+}
+
+fn read_u32(data: &[u8]) -> Result<(u32, &[u8]), ProgramError> {
+    match data.split_first_chunk::<4>() {
+        Some((value, rest)) => Ok((u32::from_le_bytes(*value), rest)),
+        None => Err(MyProgramError::InvalidInstructionData.into()),
+    }
+}
+
+/// Hands any PDA of this program over to the delegation program.
+///
+/// The PDA's seeds come after the fixed part of the instruction data and are
+/// checked by re-deriving the address. The payer has to be one of them, or
+/// the config admin when delegating the config.
 pub fn process_delegate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
-    let [maker, pda_acc, magic_acc, buffer_acc, delegation_record, delegation_metadata, system_program] =
+    let [payer, pda_acc, magic_acc, buffer_acc, delegation_record, delegation_metadata, system_program] =
         accounts
     else {
         return Err(ProgramError::NotEnoughAccountKeys);
     };
 
+    payer.check_writable()?;
+    pda_acc.check_writable()?;
+    pda_acc.check_owner(&crate::ID)?;
+    buffer_acc.check_writable()?;
+    delegation_record.check_writable()?;
+    system_program.check_program(&pinocchio_system::ID)?;
+    //the delegation program is handed the PDA's owner program
+    if magic_acc.key() != &crate::ID {
+        return Err(MyProgramError::InvalidProgram.into());
+    }
+
+    if data.len() < DelegateIx::LEN {
+        return Err(MyProgramError::InvalidInstructionData.into());
+    }
+    let (ix_data, data) = data.split_at(DelegateIx::LEN);
+    let ix_data = load_ix_data::<DelegateIx>(ix_data)?;
+    let (pda_seeds, rest) = PdaSeeds::parse(data)?;
+    if !rest.is_empty() {
+        return Err(MyProgramError::InvalidInstructionData.into());
+    }
+    pda_seeds.check_authority(payer, pda_acc)?;
+
+    let commit_frequency_ms = u32::from_le_bytes(ix_data.commit_frequency_ms);
+    if !(MIN_COMMIT_FREQUENCY_MS..=MAX_COMMIT_FREQUENCY_MS).contains(&commit_frequency_ms) {
+        return Err(MyProgramError::InvalidCommitFrequency.into());
+    }
+    //all zeroes leaves the choice of validator to the delegation program
+    let validator = (ix_data.validator != Pubkey::default()).then_some(ix_data.validator);
+
     //get buffer seeds
     let buffer_seeds: &[&[u8]] = &[b"buffer", pda_acc.key().as_ref()];
-    let escrow_seeds = &["escrow".as_bytes(), maker.key().as_ref()];
-    let delegation_rec_seeds = &[b"delegation", delegation_record.key().as_ref()];
-    let delegation_met_seeds = &[b"delegation-metadata", delegation_metadata.key().as_ref()];
 
     //find pdas
-    let (_, delegate_account_bump) = pubkey::find_program_address(escrow_seeds, &crate::ID);
-
-    let (_, buffer_pda_bump) = pubkey::find_program_address(buffer_seeds, &crate::ID);
+    let delegate_account_bump =
+        pda_acc.find_pda(pda_seeds.as_slice(), MyProgramError::PdaMismatch)?;
+    let buffer_pda_bump = buffer_acc.find_pda(buffer_seeds, MyProgramError::PdaMismatch)?;
 
     //get signer seeds
 
     let bump = [delegate_account_bump];
-    let seed_a = [
-        Seed::from(b"escrow"),
-        Seed::from(maker.key().as_ref()),
-        Seed::from(&bump),
-    ];
-    let pda_signer_seeds = Signer::from(&seed_a);
+    let seed_a = pda_seeds.signer_seeds(&bump);
+    let pda_signer_seeds = Signer::from(&seed_a[..=pda_seeds.as_slice().len()]);
 
     let bump = [buffer_pda_bump];
     let seed_b = [
@@ -71,46 +184,51 @@ pub fn process_delegate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult
 
     let buffer_signer_seeds = Signer::from(&seed_b);
 
+    //the buffer and the recreated account match whatever size the PDA has
+    let data_len = pda_acc.data_len();
+    let rent = Rent::get()?;
+
     pinocchio_system::instructions::CreateAccount {
-        from: maker,
+        from: payer,
         to: buffer_acc,
-        lamports: Rent::get()?.minimum_balance(Escrow::LEN),
-        space: Escrow::LEN as u64, //PDA acc length
+        lamports: rent.minimum_balance(data_len),
+        space: data_len as u64,
         owner: &crate::ID,
     }
-    .invoke_signed(&[buffer_signer_seeds.clone()])?;
+    .invoke_signed(&[buffer_signer_seeds])?;
 
     // Copy the date to the buffer PDA
     let mut buffer_data = buffer_acc.try_borrow_mut_data()?;
-    let new_data = pda_acc.try_borrow_data()?.to_vec().clone();
-    (*buffer_data).copy_from_slice(&new_data);
+    buffer_data.copy_from_slice(&pda_acc.try_borrow_data()?);
     drop(buffer_data);
 
     //acc needs to be closed to be delagated
 
     //zeroed lamports
     unsafe {
-        *maker.borrow_mut_lamports_unchecked() += *pda_acc.borrow_lamports_unchecked();
+        *payer.borrow_mut_lamports_unchecked() += *pda_acc.borrow_lamports_unchecked();
         *pda_acc.borrow_mut_lamports_unchecked() = 0
     };
 
     //empty data
-    pda_acc.realloc(0, false).unwrap();
+    pda_acc
+        .realloc(0, false)
+        .map_err(|_| MyProgramError::FailedRealloc)?;
     //send to System Program
     unsafe { pda_acc.assign(system_program.key()) };
 
     //we create account with Delegation Account
     pinocchio_system::instructions::CreateAccount {
-        from: maker,
+        from: payer,
         to: pda_acc,
-        lamports: Rent::get()?.minimum_balance(Escrow::LEN),
-        space: Escrow::LEN as u64, //PDA acc length
+        lamports: rent.minimum_balance(data_len),
+        space: data_len as u64,
         owner: &DELEGATION_ACCOUNT,
     }
-    .invoke_signed(&[buffer_signer_seeds])?;
+    .invoke_signed(core::slice::from_ref(&pda_signer_seeds))?;
 
     let account_metas = vec![
-        AccountMeta::new(maker.key(), true, true),
+        AccountMeta::new(payer.key(), true, true),
         AccountMeta::new(pda_acc.key(), true, false),
         AccountMeta::readonly(&crate::ID),
         AccountMeta::new(buffer_acc.key(), false, false),
@@ -122,12 +240,16 @@ pub fn process_delegate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult
     //args are the DelegateAccountArgs serialized
 
     //preprare delegate args
-    let seeds_vec: Vec<Vec<u8>> = escrow_seeds.iter().map(|&slice| slice.to_vec()).collect();
+    let seeds_vec: Vec<Vec<u8>> = pda_seeds
+        .as_slice()
+        .iter()
+        .map(|&slice| slice.to_vec())
+        .collect();
 
     let delegate_args = DelegateAccountArgs {
-        commit_frequency_ms: 30_000,
+        commit_frequency_ms,
         seeds: seeds_vec,
-        validator: None,
+        validator,
     };
 
     //seriliaze the args
@@ -144,7 +266,7 @@ pub fn process_delegate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult
     };
 
     let acc_infos = [
-        maker,
+        payer,
         pda_acc,
         magic_acc,
         buffer_acc,
@@ -153,6 +275,23 @@ pub fn process_delegate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult
         system_program,
     ];
 
-    invoke_signed(&instruction, &acc_infos, &[pda_signer_seeds]);
+    invoke_signed(&instruction, &acc_infos, &[pda_signer_seeds])?;
+
+    //the delegation program has its own copy now, undelegation restores from that
+    unsafe {
+        *payer.borrow_mut_lamports_unchecked() += *buffer_acc.borrow_lamports_unchecked();
+        *buffer_acc.borrow_mut_lamports_unchecked() = 0;
+    }
+    buffer_acc
+        .realloc(0, false)
+        .map_err(|_| MyProgramError::FailedRealloc)?;
+    unsafe { buffer_acc.assign(system_program.key()) };
+
+    DelegateEvent {
+        account: *pda_acc.key(),
+        authority: *payer.key(),
+    }
+    .emit();
+
     Ok(())
 }
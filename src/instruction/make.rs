@@ -1,104 +1,226 @@
-use bytemuck::{from_bytes, Pod, Zeroable};
+use bytemuck::{Pod, Zeroable};
 use pinocchio::{
     account_info::AccountInfo,
+    instruction::{Seed, Signer},
     program_error::ProgramError,
-    pubkey,
-    sysvars::{rent::Rent, Sysvar},
+    pubkey::Pubkey,
+    sysvars::{clock::Clock, rent::Rent, Sysvar},
     ProgramResult,
 };
 use pinocchio_log::log;
-use pinocchio_token::state::TokenAccount;
 
 use crate::{
     error::MyProgramError,
-    state::{DataLen, Escrow},
+    event::{Event, MakeEvent},
+    instruction::create_pda_account,
+    state::{load_ix_data, DataLen, Escrow},
+    token::{
+        check_token_program, is_native_mint, mint_decimals, mint_transfer_fee,
+        token_account_amount, vault_len, InitializeAccount3, TransferChecked,
+    },
+    validation::AccountCheck,
 };
 
 #[repr(C)]
 #[derive(Clone, Copy, Zeroable, Pod)]
 pub struct MakeEscrowIx {
     pub bump: u8,
+    pub seed: [u8; 8],
     pub amount_a: [u8; 8],
     pub amount_b: [u8; 8],
+    // zero means the offer never expires
+    pub expiry: [u8; 8],
+    pub bounty: [u8; 8],
+    // all zeroes leaves the offer open to anyone
+    pub taker: Pubkey,
+}
+
+impl DataLen for MakeEscrowIx {
+    const LEN: usize = core::mem::size_of::<MakeEscrowIx>();
 }
 
 pub fn process_make(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
-    let [maker, mint_a, mint_b, maker_ata, vault, escrow, _system_program, _token_program] =
-        accounts
+    let [maker, mint_a, mint_b, maker_ata, vault, escrow, system_program, token_program] = accounts
     else {
         return Err(ProgramError::NotEnoughAccountKeys);
     };
 
-    /*
-     if data.len() < core::mem::size_of::<MakeEscrowIx>() {
-         return Err(ProgramError::InvalidInstructionData);
-     }
+    maker.check_signer()?;
+    maker.check_writable()?;
+    vault.check_writable()?;
+    escrow.check_writable()?;
+    system_program.check_program(&pinocchio_system::ID)?;
 
-     let ix_data: &MakeEscrowIx = from_bytes(data);
-    */
+    let MakeEscrowIx {
+        bump,
+        seed,
+        amount_a,
+        amount_b,
+        expiry,
+        bounty,
+        taker,
+    } = load_ix_data::<MakeEscrowIx>(data)?;
+    let amount_a = u64::from_le_bytes(amount_a);
 
-    //without bytemuck and raw pointers:
-    if data.len() < 17 {
-        return Err(ProgramError::InvalidInstructionData);
+    //an offer asking for nothing could be taken for free
+    if u64::from_le_bytes(amount_b) == 0 {
+        return Err(MyProgramError::InvalidAmount.into());
     }
-    let bump = data[0];
-    let amount_a = u64::from_le_bytes(
-        data[1..9]
-            .try_into()
-            .map_err(|_| MyProgramError::DeserializationFailed)?,
-    );
-    let amount_b = data[9..17]
-        .try_into()
-        .map_err(|_| MyProgramError::DeserializationFailed)?;
 
-    let seeds = &["escrow".as_bytes(), maker.key().as_ref()];
+    // zero means the offer never expires
+    let expiry_ts = i64::from_le_bytes(expiry);
+    if expiry_ts != 0 && expiry_ts <= Clock::get()?.unix_timestamp {
+        return Err(MyProgramError::InvalidExpiry.into());
+    }
 
-    let (pda, bump_1) =
-        pubkey::try_find_program_address(seeds, &crate::ID).ok_or(ProgramError::InvalidSeeds)?;
+    let bump_1 = escrow.find_pda(
+        &[b"escrow", maker.key(), &seed],
+        MyProgramError::EscrowPdaMismatch,
+    )?;
+    //the client sends the bump it derived, only the canonical one is accepted
+    if bump != bump_1 {
+        return Err(MyProgramError::EscrowPdaMismatch.into());
+    }
 
     log!("bomp {}", bump_1);
-    assert_eq!(&pda, escrow.key());
 
-    //is escrow the vault onwer?
-    assert!(unsafe {
-        TokenAccount::from_account_info_unchecked(vault)
-            .map_err(|_| MyProgramError::DeserializationFailed)?
-            .owner()
-            == escrow.key()
-    });
+    //native SOL legs are settled in lamports instead of tokens
+    let native_a = is_native_mint(mint_a);
+    let native_b = is_native_mint(mint_b);
+
+    //the vault is a PDA created below, so it can't be pre-seeded
+    let vault_bump = vault.find_pda(&[b"vault", escrow.key()], MyProgramError::PdaMismatch)?;
+
+    //mint_a can live under either token program
+    if !native_a {
+        maker_ata.check_writable()?;
+        check_token_program(mint_a, token_program)?;
+    }
+
+    //reject Token-2022 mints the escrow cannot hold or settle
+    let epoch = Clock::get()?.epoch;
+    let transfer_fee_a = if native_a {
+        None
+    } else {
+        mint_transfer_fee(mint_a, epoch)?
+    };
+    if !native_b {
+        mint_transfer_fee(mint_b, epoch)?;
+    }
 
     //has the escrow bee initialized? - check or lamports and data
     if unsafe { escrow.owner() } == &crate::ID {
         return Err(ProgramError::AccountAlreadyInitialized);
     }
 
+    let bump = [bump_1];
+    let signer_seeds = [
+        Seed::from(b"escrow"),
+        Seed::from(maker.key()),
+        Seed::from(&seed),
+        Seed::from(&bump),
+    ];
+
     // Create Escrow Account
-    pinocchio_system::instructions::CreateAccount {
-        from: maker,
-        to: escrow,
-        lamports: Rent::get()?.minimum_balance(Escrow::LEN),
-        space: Escrow::LEN as u64,
-        owner: &crate::ID,
-    }
-    .invoke()?;
+    create_pda_account(
+        maker,
+        escrow,
+        Rent::get()?.minimum_balance(Escrow::LEN),
+        Escrow::LEN,
+        &crate::ID,
+        Signer::from(&signer_seeds),
+    )?;
+
+    let bump = [vault_bump];
+    let vault_seeds = [
+        Seed::from(b"vault"),
+        Seed::from(escrow.key()),
+        Seed::from(&bump),
+    ];
+
+    let deposit = if native_a {
+        //lamports are held directly by the program-owned vault
+        create_pda_account(
+            maker,
+            vault,
+            Rent::get()?
+                .minimum_balance(0)
+                .checked_add(amount_a)
+                .ok_or(ProgramError::ArithmeticOverflow)?,
+            0,
+            &crate::ID,
+            Signer::from(&vault_seeds),
+        )?;
+
+        amount_a
+    } else {
+        //a token account of mint_a owned by the escrow
+        let space = vault_len(transfer_fee_a.is_some());
+        create_pda_account(
+            maker,
+            vault,
+            Rent::get()?.minimum_balance(space),
+            space,
+            token_program.key(),
+            Signer::from(&vault_seeds),
+        )?;
+
+        InitializeAccount3 {
+            account: vault,
+            mint: mint_a,
+            owner: escrow.key(),
+            token_program: token_program.key(),
+        }
+        .invoke()?;
+
+        TransferChecked {
+            from: maker_ata,
+            mint: mint_a,
+            to: vault,
+            authority: maker,
+            amount: amount_a,
+            decimals: mint_decimals(mint_a)?,
+            token_program: token_program.key(),
+        }
+        .invoke()?;
+
+        //transfer fees are withheld from what lands in the vault
+        token_account_amount(vault)?
+    };
 
     // Populate Escrow Account
     Escrow::initialize(
         escrow,
-        *maker.key(),
-        *mint_a.key(),
-        *mint_b.key(),
-        amount_b,
-        bump_1,
+        Escrow {
+            discriminator: Escrow::DISCRIMINATOR,
+            version: Escrow::VERSION,
+            maker: *maker.key(),
+            seed,
+            mint_a: *mint_a.key(),
+            mint_b: *mint_b.key(),
+            taker,
+            amount: amount_b,
+            deposit: deposit.to_le_bytes(),
+            expiry,
+            bounty,
+            native_a: native_a as u8,
+            native_b: native_b as u8,
+            bump: bump_1,
+        },
     );
 
-    pinocchio_token::instructions::Transfer {
-        from: maker_ata,
-        to: vault,
-        authority: maker,
-        amount: amount_a,
+    MakeEvent {
+        escrow: *escrow.key(),
+        maker: *maker.key(),
+        mint_a: *mint_a.key(),
+        mint_b: *mint_b.key(),
+        taker,
+        deposit: deposit.to_le_bytes(),
+        amount: amount_b,
+        expiry,
+        bounty,
     }
-    .invoke()?;
+    .emit();
 
     Ok(())
 }
@@ -0,0 +1,99 @@
+use pinocchio::{
+    account_info::AccountInfo,
+    instruction::{Seed, Signer},
+    program_error::ProgramError,
+    ProgramResult,
+};
+
+use crate::{
+    error::MyProgramError,
+    event::{Event, MigrateEvent},
+    state::{DataLen, Escrow, EscrowV1},
+    token::{
+        check_token_program, check_vault_owner, close_vault, mint_decimals, token_account_amount,
+        token_account_mint, TransferChecked,
+    },
+    validation::{check_escrow_owner, AccountCheck},
+};
+
+/// Retires a v1 escrow. Its address and vault predate the seeded PDAs the
+/// other instructions check, so rather than upgrading it in place the deposit
+/// goes back to the maker and the escrow is closed. The maker can re-make the
+/// offer on the current layout.
+pub fn process_migrate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [maker, mint_a, maker_ata_a, vault, escrow, token_program] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+
+    //migrate takes no instruction data
+    if !data.is_empty() {
+        return Err(MyProgramError::InvalidInstructionData.into());
+    }
+
+    maker.check_signer()?;
+    maker.check_writable()?;
+    maker_ata_a.check_writable()?;
+    vault.check_writable()?;
+    escrow.check_writable()?;
+    check_escrow_owner(escrow)?;
+    check_token_program(mint_a, token_program)?;
+
+    //v1 escrows are recognised by their size, they have no header
+    if escrow.data_len() == Escrow::LEN {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    if escrow.data_len() != EscrowV1::LEN {
+        return Err(MyProgramError::InvalidDiscriminator.into());
+    }
+
+    let v1: EscrowV1 = bytemuck::pod_read_unaligned(&escrow.try_borrow_data()?);
+    if v1.mint_a != *mint_a.key() {
+        return Err(MyProgramError::MintMismatch.into());
+    }
+    escrow.check_pda(
+        &[b"escrow", maker.key(), &[v1.bump]],
+        MyProgramError::EscrowPdaMismatch,
+    )?;
+
+    //the v1 vault is any token account of mint_a the escrow owns
+    check_vault_owner(vault, escrow, false)?;
+    if token_account_mint(vault)? != v1.mint_a {
+        return Err(MyProgramError::MintMismatch.into());
+    }
+
+    let bump = [v1.bump];
+    let seed = [
+        Seed::from(b"escrow"),
+        Seed::from(maker.key()),
+        Seed::from(&bump),
+    ];
+    let seeds = Signer::from(&seed);
+    let amount = token_account_amount(vault)?;
+
+    TransferChecked {
+        from: vault,
+        mint: mint_a,
+        to: maker_ata_a,
+        authority: escrow,
+        amount,
+        decimals: mint_decimals(mint_a)?,
+        token_program: token_program.key(),
+    }
+    .invoke_signed(core::slice::from_ref(&seeds))?;
+
+    close_vault(vault, mint_a, maker, escrow, token_program.key(), &[seeds])?;
+
+    unsafe {
+        *maker.borrow_mut_lamports_unchecked() += *escrow.borrow_lamports_unchecked();
+        *escrow.borrow_mut_lamports_unchecked() = 0;
+    }
+
+    MigrateEvent {
+        escrow: *escrow.key(),
+        maker: *maker.key(),
+        refunded: amount.to_le_bytes(),
+    }
+    .emit();
+
+    Ok(())
+}
@@ -1,37 +1,131 @@
+pub mod amend;
+pub mod commit;
+pub mod config;
 pub mod delegate;
 pub mod make;
+pub mod migrate;
 pub mod refund;
 pub mod take;
 pub mod undelegate;
 
+pub use amend::*;
+pub use commit::*;
+pub use config::*;
 pub use delegate::*;
 pub use make::*;
+pub use migrate::*;
 pub use refund::*;
 pub use take::*;
 pub use undelegate::*;
 
-use pinocchio::program_error::ProgramError;
+use core::ops::RangeInclusive;
 
+use pinocchio::{
+    account_info::AccountInfo, instruction::Signer, program_error::ProgramError, pubkey::Pubkey,
+    ProgramResult,
+};
+
+/// First byte of the instruction data. Values are stable, append new
+/// instructions below the last one and never reuse a retired value.
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MyProgramInstrution {
-    Make,
-    Take,
-    Refund,
-    Delegate,
-    UnDelegate,
+    Make = 0,
+    Take = 1,
+    Refund = 2,
+    Delegate = 3,
+    // retired, delegation ends with CommitAndUndelegate and the delegation
+    // program's callback
+    UnDelegate = 4,
+    InitConfig = 5,
+    UpdateConfig = 6,
+    Amend = 7,
+    Migrate = 8,
+    Commit = 9,
+    CommitAndUndelegate = 10,
+}
+
+impl MyProgramInstrution {
+    /// Every instruction, indexed by its discriminator.
+    pub const ALL: [MyProgramInstrution; 11] = [
+        MyProgramInstrution::Make,
+        MyProgramInstrution::Take,
+        MyProgramInstrution::Refund,
+        MyProgramInstrution::Delegate,
+        MyProgramInstrution::UnDelegate,
+        MyProgramInstrution::InitConfig,
+        MyProgramInstrution::UpdateConfig,
+        MyProgramInstrution::Amend,
+        MyProgramInstrution::Migrate,
+        MyProgramInstrution::Commit,
+        MyProgramInstrution::CommitAndUndelegate,
+    ];
+
+    /// Never assigned to an instruction, kept free for callbacks from other
+    /// programs that share our entrypoint with their own discriminators.
+    pub const RESERVED: RangeInclusive<u8> = 128..=u8::MAX;
 }
 
+// ALL has to stay in discriminator order and clear of the reserved range
+const _: () = {
+    let mut i = 0;
+    while i < MyProgramInstrution::ALL.len() {
+        assert!(MyProgramInstrution::ALL[i] as usize == i);
+        assert!((i as u8) < *MyProgramInstrution::RESERVED.start());
+        i += 1;
+    }
+    assert!(UNDELEGATE_DISCRIMINATOR[0] >= *MyProgramInstrution::RESERVED.start());
+};
+
 impl TryFrom<&u8> for MyProgramInstrution {
     type Error = ProgramError;
 
     fn try_from(value: &u8) -> Result<Self, Self::Error> {
-        match *value {
-            0 => Ok(MyProgramInstrution::Make),
-            1 => Ok(MyProgramInstrution::Take),
-            2 => Ok(MyProgramInstrution::Refund),
-            3 => Ok(MyProgramInstrution::Delegate),
-            3 => Ok(MyProgramInstrution::UnDelegate),
-            _ => Err(ProgramError::InvalidInstructionData),
+        MyProgramInstrution::ALL
+            .get(*value as usize)
+            .copied()
+            .ok_or(ProgramError::InvalidInstructionData)
+    }
+}
+
+/// Creates the PDA `account` holding `lamports`, with `space` bytes owned by
+/// `owner`. Anyone can send lamports to a PDA ahead of time, which would make
+/// `CreateAccount` fail, so a funded account is topped up, allocated and
+/// assigned instead.
+pub(crate) fn create_pda_account(
+    payer: &AccountInfo,
+    account: &AccountInfo,
+    lamports: u64,
+    space: usize,
+    owner: &Pubkey,
+    signer: Signer,
+) -> ProgramResult {
+    let funded = account.lamports();
+    if funded == 0 {
+        return pinocchio_system::instructions::CreateAccount {
+            from: payer,
+            to: account,
+            lamports,
+            space: space as u64,
+            owner,
+        }
+        .invoke_signed(&[signer]);
+    }
+
+    if funded < lamports {
+        pinocchio_system::instructions::Transfer {
+            from: payer,
+            to: account,
+            lamports: lamports - funded,
         }
+        .invoke()?;
     }
+
+    pinocchio_system::instructions::Allocate {
+        account,
+        space: space as u64,
+    }
+    .invoke_signed(core::slice::from_ref(&signer))?;
+
+    pinocchio_system::instructions::Assign { account, owner }.invoke_signed(&[signer])
 }
@@ -2,65 +2,152 @@ use pinocchio::{
     account_info::AccountInfo,
     instruction::{Seed, Signer},
     program_error::ProgramError,
-    pubkey::find_program_address,
+    sysvars::{clock::Clock, Sysvar},
     ProgramResult,
 };
 
 use crate::{
     error::MyProgramError,
-    state::{load_acc_mut_unchecked, Escrow},
+    event::{Event, RefundEvent},
+    state::Escrow,
+    token::{
+        check_token_program, check_vault_owner, close_vault, mint_decimals, token_account_amount,
+        token_account_owner, TransferChecked,
+    },
+    validation::{check_escrow_owner, AccountCheck},
 };
 
-pub fn process_refund(accounts: &[AccountInfo]) -> ProgramResult {
-    let [maker, mint_a, maker_ata_a, vault, escrow, _token_program, _system_program] = accounts
+/// Returns the vault to the maker and closes the escrow.
+///
+/// The maker can refund at any time by signing. Once the offer has expired
+/// anyone can crank the refund; a signing cranker passed as a trailing account
+/// is paid the escrow's bounty out of its rent.
+pub fn process_refund(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [maker, mint_a, maker_ata_a, vault, escrow, token_program, system_program, remaining @ ..] =
+        accounts
     else {
-        return Err(pinocchio::program_error::ProgramError::NotEnoughAccountKeys);
+        return Err(ProgramError::NotEnoughAccountKeys);
     };
 
+    //refund takes no instruction data
+    if !data.is_empty() {
+        return Err(MyProgramError::InvalidInstructionData.into());
+    }
+
+    maker.check_writable()?;
+    vault.check_writable()?;
+    escrow.check_writable()?;
+    system_program.check_program(&pinocchio_system::ID)?;
+    check_escrow_owner(escrow)?;
+
     //try to load escrow data:
-    let escrow_data = escrow
-        .try_borrow_data()
-        .map_err(|_| ProgramError::AccountBorrowFailed)?;
-    let escrow_account = bytemuck::try_from_bytes::<Escrow>(&escrow_data)
-        .map_err(|_| MyProgramError::DeserializationFailed)?;
-    ();
+    let escrow_account = Escrow::load(escrow)?;
+
+    if escrow_account.mint_a != *mint_a.key() {
+        return Err(MyProgramError::MintMismatch.into());
+    }
 
-    assert_eq!(escrow_account.mint_a, *mint_a.key());
+    //a native vault holds lamports rather than tokens
+    let native_a = escrow_account.native_a != 0;
+    vault.find_pda(&[b"vault", escrow.key()], MyProgramError::PdaMismatch)?;
+    check_vault_owner(vault, escrow, native_a)?;
+    if !native_a {
+        maker_ata_a.check_writable()?;
+        check_token_program(mint_a, token_program)?;
+    }
 
-    let vault_account = pinocchio_token::state::TokenAccount::from_account_info(vault)?;
+    // without the maker's signature only an expired offer can be refunded
+    let cranker = if maker.is_signer() {
+        None
+    } else {
+        if !escrow_account.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(MyProgramError::EscrowNotExpired.into());
+        }
+        //the cranker picks maker_ata_a, the deposit must still reach the maker
+        if !native_a && token_account_owner(maker_ata_a)? != *maker.key() {
+            return Err(MyProgramError::InvalidAccount.into());
+        }
+        let cranker = remaining.first();
+        if let Some(cranker) = cranker {
+            cranker.check_signer()?;
+            cranker.check_writable()?;
+        }
+        cranker
+    };
+    let bounty = u64::from_le_bytes(escrow_account.bounty);
 
-    let seed = [(b"escrow"), maker.key().as_slice(), &[escrow_account.bump]];
-    let seeds = &seed[..];
-    let escrow_pda = find_program_address(seeds, &crate::ID).0;
-    assert_eq!(*escrow.key(), escrow_pda);
+    escrow.check_pda(
+        &[
+            b"escrow",
+            maker.key(),
+            &escrow_account.seed,
+            &[escrow_account.bump],
+        ],
+        MyProgramError::EscrowPdaMismatch,
+    )?;
 
+    let escrow_seed = escrow_account.seed;
     let bump = [escrow_account.bump];
-    let seed = [
-        Seed::from(b"escrow"),
-        Seed::from(maker.key()),
-        Seed::from(&bump),
-    ];
-    let seeds = Signer::from(&seed);
-
-    pinocchio_token::instructions::Transfer {
-        from: vault,
-        to: maker_ata_a,
-        authority: escrow,
-        amount: vault_account.amount(),
-    }
-    .invoke_signed(&[seeds.clone()])?; //we should avoid cloning
+    let deposit = u64::from_le_bytes(escrow_account.deposit);
+    drop(escrow_account);
 
-    pinocchio_token::instructions::CloseAccount {
-        account: vault,
-        destination: maker,
-        authority: escrow,
-    }
-    .invoke_signed(&[seeds])?;
+    let refunded = if native_a {
+        //the whole vault, deposit and rent, goes back to the maker
+        unsafe {
+            *maker.borrow_mut_lamports_unchecked() += *vault.borrow_lamports_unchecked();
+            *vault.borrow_mut_lamports_unchecked() = 0;
+        }
+
+        deposit
+    } else {
+        let seed = [
+            Seed::from(b"escrow"),
+            Seed::from(maker.key()),
+            Seed::from(&escrow_seed),
+            Seed::from(&bump),
+        ];
+        let seeds = Signer::from(&seed);
+        let amount = token_account_amount(vault)?;
+
+        TransferChecked {
+            from: vault,
+            mint: mint_a,
+            to: maker_ata_a,
+            authority: escrow,
+            amount,
+            decimals: mint_decimals(mint_a)?,
+            token_program: token_program.key(),
+        }
+        .invoke_signed(core::slice::from_ref(&seeds))?;
+
+        close_vault(vault, mint_a, maker, escrow, token_program.key(), &[seeds])?;
 
-    unsafe {
-        *maker.borrow_mut_lamports_unchecked() += *escrow.borrow_lamports_unchecked();
-        *escrow.borrow_mut_lamports_unchecked() = 0
+        amount
     };
 
+    let bounty = unsafe {
+        let lamports = *escrow.borrow_lamports_unchecked();
+        let bounty = match cranker {
+            Some(cranker) => {
+                let bounty = bounty.min(lamports);
+                *cranker.borrow_mut_lamports_unchecked() += bounty;
+                bounty
+            }
+            None => 0,
+        };
+        *maker.borrow_mut_lamports_unchecked() += lamports - bounty;
+        *escrow.borrow_mut_lamports_unchecked() = 0;
+        bounty
+    };
+
+    RefundEvent {
+        escrow: *escrow.key(),
+        maker: *maker.key(),
+        refunded: refunded.to_le_bytes(),
+        cranker: cranker.map_or([0; 32], |cranker| *cranker.key()),
+        bounty: bounty.to_le_bytes(),
+    }
+    .emit();
+
     Ok(())
 }
@@ -1,79 +1,287 @@
+use bytemuck::{Pod, Zeroable};
 use pinocchio::{
     account_info::AccountInfo,
     instruction::{Seed, Signer},
     program_error::ProgramError,
-    pubkey::find_program_address,
+    sysvars::{clock::Clock, Sysvar},
     ProgramResult,
 };
 
 use crate::{
     error::MyProgramError,
-    state::{load_acc_mut_unchecked, Escrow},
+    event::{Event, TakeEvent},
+    state::{load_acc, load_ix_data, Config, DataLen, Escrow},
+    token::{
+        check_token_program, check_vault_owner, close_vault, mint_decimals, mint_transfer_fee,
+        token_account_amount, token_account_mint, token_account_owner, TransferChecked,
+    },
+    validation::{check_escrow_owner, AccountCheck},
 };
 
-pub fn process_take(accounts: &[AccountInfo]) -> ProgramResult {
-    let [taker, maker, mint_a, mint_b, taker_ata_a, taker_ata_b, maker_ata_b, vault, escrow, _token_program, _system_program] =
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+pub struct TakeEscrowIx {
+    // amount of mint_b the taker pays in this fill
+    pub amount: [u8; 8],
+}
+
+impl DataLen for TakeEscrowIx {
+    const LEN: usize = core::mem::size_of::<TakeEscrowIx>();
+}
+
+/// Protocol fee charged on a fill of `amount` mint_b, rounded down so dust
+/// fills pay no fee rather than overpaying.
+pub fn protocol_fee(amount: u64, fee_bps: u16) -> u64 {
+    (amount as u128 * fee_bps as u128 / 10_000) as u64
+}
+
+/// Amount of mint_a released for paying `pay_b` out of the `remaining_b` still
+/// requested, rounded down so the vault can never be drained ahead of the price.
+/// The last fill always releases whatever is left in the vault.
+pub fn release_amount(remaining_a: u64, remaining_b: u64, pay_b: u64) -> Option<u64> {
+    if pay_b == 0 || pay_b > remaining_b {
+        return None;
+    }
+    if pay_b == remaining_b {
+        return Some(remaining_a);
+    }
+    let release = (remaining_a as u128 * pay_b as u128 / remaining_b as u128) as u64;
+    (release != 0).then_some(release)
+}
+
+pub fn process_take(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [taker, maker, mint_a, mint_b, taker_ata_a, taker_ata_b, maker_ata_b, vault, escrow, token_program_a, token_program_b, system_program, config, fee_recipient_b] =
         accounts
     else {
-        return Err(pinocchio::program_error::ProgramError::NotEnoughAccountKeys);
+        return Err(ProgramError::NotEnoughAccountKeys);
     };
 
-    //here is where we could use bytemuck
+    taker.check_signer()?;
+    taker.check_writable()?;
+    maker.check_writable()?;
+    vault.check_writable()?;
+    escrow.check_writable()?;
+    fee_recipient_b.check_writable()?;
+    system_program.check_program(&pinocchio_system::ID)?;
+    check_escrow_owner(escrow)?;
+
+    let ix_data = load_ix_data::<TakeEscrowIx>(data)?;
+    let pay_b = u64::from_le_bytes(ix_data.amount);
+
+    //the program config sets the protocol fee, until it's created fills are free
+    let (fee_bps, fee_recipient) = if unsafe { config.owner() } == &crate::ID {
+        let config_data = config.try_borrow_data()?;
+        let config_account = load_acc::<Config>(&config_data)?;
+        config.check_pda(
+            &[Config::SEED, &[config_account.bump]],
+            MyProgramError::PdaMismatch,
+        )?;
+        (config_account.fee_bps(), config_account.fee_recipient)
+    } else {
+        //still has to be the config's address, or any account would dodge the fee
+        config.find_pda(&[Config::SEED], MyProgramError::PdaMismatch)?;
+        (0, [0; 32])
+    };
 
     //try to load escrow data:
-    let escrow_data = escrow
-        .try_borrow_data()
-        .map_err(|_| ProgramError::AccountBorrowFailed)?;
-    let escrow_account = bytemuck::try_from_bytes::<Escrow>(&escrow_data)
-        .map_err(|_| MyProgramError::DeserializationFailed)?;
-    ();
-    /*
-        let escrow_account =
-        unsafe { load_acc_mut_unchecked::<Escrow>(escrow.borrow_mut_data_unchecked())? };
-
-    */
-
-    //do we need this? do we need to pass mint_a and mint_b? Isnt the CPI checking this?
-    assert_eq!(escrow_account.mint_a, *mint_a.key());
-    assert_eq!(escrow_account.mint_b, *mint_b.key());
-
-    let vault_account = pinocchio_token::state::TokenAccount::from_account_info(vault)?;
-
-    let seed = [(b"escrow"), maker.key().as_slice(), &[escrow_account.bump]];
-    let seeds = &seed[..];
-    let escrow_pda = find_program_address(seeds, &crate::ID).0;
-    assert_eq!(*escrow.key(), escrow_pda);
-
-    pinocchio_token::instructions::Transfer {
-        from: taker_ata_b,
-        to: maker_ata_b,
-        authority: taker,
-        amount: u64::from_le_bytes(escrow_account.amount),
+    let mut escrow_account = Escrow::load_mut(escrow)?;
+
+    //the token CPIs would catch a wrong token mint, but not a wrong native leg
+    if escrow_account.mint_a != *mint_a.key() || escrow_account.mint_b != *mint_b.key() {
+        return Err(MyProgramError::MintMismatch.into());
+    }
+
+    escrow.check_pda(
+        &[
+            b"escrow",
+            maker.key(),
+            &escrow_account.seed,
+            &[escrow_account.bump],
+        ],
+        MyProgramError::EscrowPdaMismatch,
+    )?;
+
+    //native legs skip the token program entirely
+    let native_a = escrow_account.native_a != 0;
+    let native_b = escrow_account.native_b != 0;
+
+    vault.find_pda(&[b"vault", escrow.key()], MyProgramError::PdaMismatch)?;
+    check_vault_owner(vault, escrow, native_a)?;
+
+    //each token leg can live under either token program
+    if !native_a {
+        taker_ata_a.check_writable()?;
+        check_token_program(mint_a, token_program_a)?;
+    }
+    if !native_b {
+        taker_ata_b.check_writable()?;
+        maker_ata_b.check_writable()?;
+        check_token_program(mint_b, token_program_b)?;
+        //the taker picks maker_ata_b, it must be the maker's account of mint_b
+        if token_account_owner(maker_ata_b)? != *maker.key()
+            || token_account_mint(maker_ata_b)? != *mint_b.key()
+        {
+            return Err(MyProgramError::InvalidAccount.into());
+        }
     }
-    .invoke()?;
 
+    if !escrow_account.can_take(taker.key()) {
+        return Err(MyProgramError::InvalidTaker.into());
+    }
+
+    if escrow_account.is_expired(Clock::get()?.unix_timestamp) {
+        return Err(MyProgramError::EscrowExpired.into());
+    }
+
+    // price the fill against what is left of the offer
+    let remaining_a = u64::from_le_bytes(escrow_account.deposit);
+    let remaining_b = u64::from_le_bytes(escrow_account.amount);
+    let release_a =
+        release_amount(remaining_a, remaining_b, pay_b).ok_or(MyProgramError::InvalidFillAmount)?;
+
+    let remaining_b = remaining_b - pay_b;
+    let remaining_a = remaining_a - release_a;
+    escrow_account.amount = remaining_b.to_le_bytes();
+    escrow_account.deposit = remaining_a.to_le_bytes();
+    let filled = remaining_b == 0;
+    let escrow_seed = escrow_account.seed;
     let bump = [escrow_account.bump];
-    let seed = [
-        Seed::from(b"escrow"),
-        Seed::from(maker.key()),
-        Seed::from(&bump),
-    ];
-    let seeds = Signer::from(&seed);
-
-    pinocchio_token::instructions::Transfer {
-        from: vault,
-        to: taker_ata_a,
-        authority: escrow,
-        amount: vault_account.amount(),
+    // the escrow is passed to the CPIs below as the vault authority
+    drop(escrow_account);
+
+    // the closing fill sweeps the token vault so it can be closed
+    let release_a = if filled && !native_a {
+        token_account_amount(vault)?
+    } else {
+        release_a
+    };
+
+    //the protocol takes its cut of the payment, the maker gets the rest
+    let fee_b = protocol_fee(pay_b, fee_bps);
+    let maker_b = pay_b - fee_b;
+
+    if native_b {
+        if fee_b != 0 && fee_recipient_b.key() != &fee_recipient {
+            return Err(MyProgramError::InvalidFeeRecipient.into());
+        }
+
+        pinocchio_system::instructions::Transfer {
+            from: taker,
+            to: maker,
+            lamports: maker_b,
+        }
+        .invoke()?;
+
+        if fee_b != 0 {
+            pinocchio_system::instructions::Transfer {
+                from: taker,
+                to: fee_recipient_b,
+                lamports: fee_b,
+            }
+            .invoke()?;
+        }
+    } else {
+        if fee_b != 0 && token_account_owner(fee_recipient_b)? != fee_recipient {
+            return Err(MyProgramError::InvalidFeeRecipient.into());
+        }
+
+        //each side receives exactly its share, the taker covers any transfer fee
+        let transfer_fee = mint_transfer_fee(mint_b, Clock::get()?.epoch)?;
+        let gross = |amount: u64| match transfer_fee {
+            Some(transfer_fee) => transfer_fee
+                .pre_fee_amount(amount)
+                .ok_or(ProgramError::ArithmeticOverflow),
+            None => Ok(amount),
+        };
+        let decimals_b = mint_decimals(mint_b)?;
+
+        TransferChecked {
+            from: taker_ata_b,
+            mint: mint_b,
+            to: maker_ata_b,
+            authority: taker,
+            amount: gross(maker_b)?,
+            decimals: decimals_b,
+            token_program: token_program_b.key(),
+        }
+        .invoke()?;
+
+        if fee_b != 0 {
+            TransferChecked {
+                from: taker_ata_b,
+                mint: mint_b,
+                to: fee_recipient_b,
+                authority: taker,
+                amount: gross(fee_b)?,
+                decimals: decimals_b,
+                token_program: token_program_b.key(),
+            }
+            .invoke()?;
+        }
+    }
+
+    TakeEvent {
+        escrow: *escrow.key(),
+        taker: *taker.key(),
+        paid: pay_b.to_le_bytes(),
+        fee: fee_b.to_le_bytes(),
+        released: release_a.to_le_bytes(),
+        remaining_a: remaining_a.to_le_bytes(),
+        remaining_b: remaining_b.to_le_bytes(),
     }
-    .invoke_signed(&[seeds.clone()])?;
+    .emit();
+
+    if native_a {
+        //the vault is program owned, lamports move without a CPI
+        unsafe {
+            *vault.borrow_mut_lamports_unchecked() -= release_a;
+            *taker.borrow_mut_lamports_unchecked() += release_a;
+        }
+
+        // partially filled offers stay open for the next taker
+        if !filled {
+            return Ok(());
+        }
+
+        //the vault's rent goes back to the maker
+        unsafe {
+            *maker.borrow_mut_lamports_unchecked() += *vault.borrow_lamports_unchecked();
+            *vault.borrow_mut_lamports_unchecked() = 0;
+        }
+    } else {
+        let seed = [
+            Seed::from(b"escrow"),
+            Seed::from(maker.key()),
+            Seed::from(&escrow_seed),
+            Seed::from(&bump),
+        ];
+        let seeds = Signer::from(&seed);
+
+        TransferChecked {
+            from: vault,
+            mint: mint_a,
+            to: taker_ata_a,
+            authority: escrow,
+            amount: release_a,
+            decimals: mint_decimals(mint_a)?,
+            token_program: token_program_a.key(),
+        }
+        .invoke_signed(core::slice::from_ref(&seeds))?;
+
+        // partially filled offers stay open for the next taker
+        if !filled {
+            return Ok(());
+        }
 
-    pinocchio_token::instructions::CloseAccount {
-        account: vault,
-        destination: maker,
-        authority: escrow,
+        close_vault(
+            vault,
+            mint_a,
+            maker,
+            escrow,
+            token_program_a.key(),
+            &[seeds],
+        )?;
     }
-    .invoke_signed(&[seeds])?;
 
     unsafe {
         *maker.borrow_mut_lamports_unchecked() += *escrow.borrow_lamports_unchecked();
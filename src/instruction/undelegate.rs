@@ -1,115 +1,77 @@
-use crate::{
-    error::MyProgramError,
-    state::{DataLen, Escrow},
-};
-use borsh::{to_vec, BorshDeserialize, BorshSerialize};
-use bytemuck::{from_bytes, Pod, Zeroable};
 use pinocchio::{
     account_info::AccountInfo,
-    cpi::invoke_signed,
-    instruction::{AccountMeta, Instruction, Seed, Signer},
+    instruction::Signer,
     program_error::ProgramError,
-    pubkey::{self, Pubkey},
     sysvars::{rent::Rent, Sysvar},
     ProgramResult,
 };
-use pinocchio_log::log;
 
-pub const DELEGATION_ACCOUNT: Pubkey =
-    pinocchio_pubkey::pubkey!("DELeGGvXpWV2fqJUhqcF5ZSYMS4JTLjteaAMARRSaeSh");
+use crate::{
+    error::MyProgramError,
+    event::{Event, UndelegateEvent},
+    instruction::{
+        create_pda_account,
+        delegate::{PdaSeeds, DELEGATION_ACCOUNT},
+    },
+    state::Escrow,
+    validation::AccountCheck,
+};
+
+/// Leads the delegation program's undelegate callback. Its first byte falls in
+/// `MyProgramInstrution::RESERVED`, so it can't be taken for one of ours.
+pub const UNDELEGATE_DISCRIMINATOR: [u8; 8] = [196, 28, 41, 206, 48, 37, 51, 167];
 
-pub fn process_undelegate(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
-    let [maker, pda_acc, buffer_acc, delegation_record, delegation_metadata, system_program] =
-        accounts
-    else {
+/// Called back by the delegation program once an undelegation has been
+/// committed. Recreates the PDA under this program and copies back the data
+/// the delegation program kept in its buffer.
+///
+/// The instruction data after the discriminator is the PDA's seeds, laid out
+/// as for `Delegate`.
+pub fn process_undelegate_callback(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [pda_acc, buffer_acc, payer, system_program] = accounts else {
         return Err(ProgramError::NotEnoughAccountKeys);
     };
 
-    // Get seeds
-    let buffer_seeds: &[&[u8]] = &[b"buffer", pda_acc.key().as_ref()];
-    let escrow_seeds = &["escrow".as_bytes(), maker.key().as_ref()];
-    let delegation_rec_seeds = &[b"delegation", delegation_record.key().as_ref()];
-    let delegation_met_seeds = &[b"delegation-metadata", delegation_metadata.key().as_ref()];
-
-    // Find PDAs
-    let (_, delegate_account_bump) = pubkey::find_program_address(escrow_seeds, &crate::ID);
-    let (_, buffer_pda_bump) = pubkey::find_program_address(buffer_seeds, &crate::ID);
-
-    // Get signer seeds
-    let bump = [delegate_account_bump];
-    let seed_a = [
-        Seed::from(b"escrow"),
-        Seed::from(maker.key().as_ref()),
-        Seed::from(&bump),
-    ];
-    let pda_signer_seeds = Signer::from(&seed_a);
+    //only the delegation program can sign for its buffer
+    buffer_acc.check_signer()?;
+    buffer_acc.check_owner(&DELEGATION_ACCOUNT)?;
+    payer.check_signer()?;
+    payer.check_writable()?;
+    pda_acc.check_writable()?;
+    pda_acc.check_owner(&pinocchio_system::ID)?;
+    system_program.check_program(&pinocchio_system::ID)?;
 
-    let bump = [buffer_pda_bump];
-    let seed_b = [
-        Seed::from(b"buffer"),
-        Seed::from(pda_acc.key().as_ref()),
-        Seed::from(&bump),
-    ];
-    let buffer_signer_seeds = Signer::from(&seed_b);
-
-    // Close delegated account
-    unsafe {
-        *maker.borrow_mut_lamports_unchecked() += *pda_acc.borrow_lamports_unchecked();
-        *pda_acc.borrow_mut_lamports_unchecked() = 0;
+    let (pda_seeds, rest) = PdaSeeds::parse(data)?;
+    if !rest.is_empty() {
+        return Err(MyProgramError::InvalidInstructionData.into());
     }
-    pda_acc.realloc(0, false)?;
-    unsafe { pda_acc.assign(system_program.key()) };
+    let bump = [pda_acc.find_pda(pda_seeds.as_slice(), MyProgramError::PdaMismatch)?];
+    let signer_seeds = pda_seeds.signer_seeds(&bump);
+    let pda_signer = Signer::from(&signer_seeds[..=pda_seeds.as_slice().len()]);
 
-    // Recreate original account with proper owner
-    pinocchio_system::instructions::CreateAccount {
-        from: maker,
-        to: pda_acc,
-        lamports: Rent::get()?.minimum_balance(Escrow::LEN),
-        space: Escrow::LEN as u64,
-        owner: &crate::ID,
-    }
-    .invoke_signed(&[pda_signer_seeds.clone()])?;
+    //the delegation program closed the account but may have left lamports in it
+    let data_len = buffer_acc.data_len();
+    create_pda_account(
+        payer,
+        pda_acc,
+        Rent::get()?.minimum_balance(data_len),
+        data_len,
+        &crate::ID,
+        pda_signer,
+    )?;
 
-    // Restore data from buffer
-    let buffer_data = buffer_acc.try_borrow_data()?;
-    let mut pda_data = pda_acc.try_borrow_mut_data()?;
-    pda_data.copy_from_slice(&buffer_data);
-    drop(pda_data);
-    drop(buffer_data);
+    pda_acc
+        .try_borrow_mut_data()?
+        .copy_from_slice(&buffer_acc.try_borrow_data()?);
 
-    // Close buffer account
-    unsafe {
-        *maker.borrow_mut_lamports_unchecked() += *buffer_acc.borrow_lamports_unchecked();
-        *buffer_acc.borrow_mut_lamports_unchecked() = 0;
+    //only escrows are tracked by events
+    if let Ok(escrow) = Escrow::from_bytes(&pda_acc.try_borrow_data()?) {
+        UndelegateEvent {
+            escrow: *pda_acc.key(),
+            maker: escrow.maker,
+        }
+        .emit();
     }
-    buffer_acc.realloc(0, false)?;
-    unsafe { buffer_acc.assign(system_program.key()) };
-
-    // Create instruction for delegation program
-    let account_metas = vec![
-        AccountMeta::new(maker.key(), true, true),
-        AccountMeta::new(pda_acc.key(), true, false),
-        AccountMeta::new(buffer_acc.key(), false, false),
-        AccountMeta::new(delegation_record.key(), true, false),
-        AccountMeta::readonly(delegation_metadata.key()),
-        AccountMeta::readonly(system_program.key()),
-    ];
-
-    let instruction = Instruction {
-        program_id: &DELEGATION_ACCOUNT,
-        accounts: &account_metas,
-        data,
-    };
-
-    let acc_infos = [
-        maker,
-        pda_acc,
-        buffer_acc,
-        delegation_record,
-        delegation_metadata,
-        system_program,
-    ];
 
-    invoke_signed(&instruction, &acc_infos, &[pda_signer_seeds])?;
     Ok(())
 }
@@ -6,9 +6,14 @@ mod entrypoint;
 #[cfg(feature = "std")]
 extern crate std;
 
-mod error;
+#[cfg(feature = "std")]
+pub mod client;
+pub mod error;
+pub mod event;
 mod instruction;
 mod state;
 mod tests;
+mod token;
+mod validation;
 
 pinocchio_pubkey::declare_id!("A24MN2mj3aBpDLRhY6FonnbTuayv7oRqhva2R2hUuyqx");
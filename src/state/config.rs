@@ -0,0 +1,48 @@
+use bytemuck::{Pod, Zeroable};
+use pinocchio::{account_info::AccountInfo, pubkey::Pubkey};
+
+use super::{DataLen, Initialized};
+
+/// Program-wide settings, stored in the `[b"config"]` PDA.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct Config {
+    // tells the config apart from escrows
+    pub discriminator: u8,
+    // the only key allowed to update the config
+    pub admin: Pubkey,
+    // wallet whose mint_b accounts receive the protocol fee
+    pub fee_recipient: Pubkey,
+    // fee charged on every fill, in basis points of the mint_b payment
+    pub fee_bps: [u8; 2],
+    pub bump: u8,
+}
+
+impl DataLen for Config {
+    const LEN: usize = core::mem::size_of::<Config>();
+}
+
+impl Initialized for Config {
+    fn is_initialized(&self) -> bool {
+        self.discriminator == Self::DISCRIMINATOR
+    }
+}
+
+impl Config {
+    pub const DISCRIMINATOR: u8 = 2;
+    pub const SEED: &'static [u8] = b"config";
+
+    /// Upper bound on `fee_bps`, 10% of every fill.
+    pub const MAX_FEE_BPS: u16 = 1_000;
+
+    pub fn initialize(config_acc: &AccountInfo, state: Config) {
+        let config =
+            unsafe { &mut *(config_acc.borrow_mut_data_unchecked().as_ptr() as *mut Self) };
+
+        *config = state;
+    }
+
+    pub fn fee_bps(&self) -> u16 {
+        u16::from_le_bytes(self.fee_bps)
+    }
+}
@@ -1,5 +1,8 @@
+pub mod config;
+#[allow(clippy::module_inception)]
 pub mod state;
 pub mod utils;
 
+pub use config::*;
 pub use state::*;
 pub use utils::*;
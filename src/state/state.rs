@@ -1,15 +1,33 @@
 use bytemuck::{Pod, Zeroable};
-use pinocchio::{account_info::AccountInfo, pubkey::Pubkey};
+use pinocchio::{
+    account_info::{AccountInfo, Ref, RefMut},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
 
-use super::DataLen;
+use super::{load_acc, load_account, load_account_mut, DataLen, Initialized};
+use crate::error::MyProgramError;
 
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub struct Escrow {
+    // tells an escrow apart from the other accounts the program owns
+    pub discriminator: u8,
+    // layout version, bumped whenever fields are added
+    pub version: u8,
     pub maker: Pubkey,
+    pub seed: [u8; 8],
     pub mint_a: Pubkey,
     pub mint_b: Pubkey,
+    // the only account allowed to take, all zeroes for open offers
+    pub taker: Pubkey,
     pub amount: [u8; 8],
+    pub deposit: [u8; 8],
+    pub expiry: [u8; 8],
+    pub bounty: [u8; 8],
+    // legs settled in native SOL rather than tokens
+    pub native_a: u8,
+    pub native_b: u8,
     pub bump: u8,
 }
 
@@ -17,22 +35,89 @@ impl DataLen for Escrow {
     const LEN: usize = core::mem::size_of::<Escrow>();
 }
 
+impl Initialized for Escrow {
+    fn is_initialized(&self) -> bool {
+        self.discriminator == Self::DISCRIMINATOR
+    }
+}
+
 impl Escrow {
-    pub fn initialize(
-        escrow_acc: &AccountInfo,
-        maker: Pubkey,
-        mint_a: Pubkey,
-        mint_b: Pubkey,
-        amount: [u8; 8],
-        bump: u8,
-    ) {
+    pub const DISCRIMINATOR: u8 = 1;
+    pub const VERSION: u8 = 2;
+
+    /// Borrows an escrow of the current version held by this program.
+    pub fn load(escrow: &AccountInfo) -> Result<Ref<'_, Self>, ProgramError> {
+        check_len(escrow.data_len())?;
+        let escrow = load_account::<Self>(escrow, &crate::ID)?;
+        escrow.check_version()?;
+        Ok(escrow)
+    }
+
+    /// Mutable counterpart of [`Escrow::load`].
+    pub fn load_mut(escrow: &AccountInfo) -> Result<RefMut<'_, Self>, ProgramError> {
+        check_len(escrow.data_len())?;
+        let escrow = load_account_mut::<Self>(escrow, &crate::ID)?;
+        escrow.check_version()?;
+        Ok(escrow)
+    }
+
+    /// Reads an escrow of the current version out of raw account data, for
+    /// callers that only have the bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<&Self, ProgramError> {
+        check_len(bytes.len())?;
+        let escrow = load_acc::<Self>(bytes)?;
+        escrow.check_version()?;
+        Ok(escrow)
+    }
+
+    fn check_version(&self) -> Result<(), ProgramError> {
+        if self.version != Self::VERSION {
+            return Err(MyProgramError::UnsupportedVersion.into());
+        }
+        Ok(())
+    }
+
+    pub fn initialize(escrow_acc: &AccountInfo, state: Escrow) {
         let escrow =
             unsafe { &mut *(escrow_acc.borrow_mut_data_unchecked().as_ptr() as *mut Self) };
 
-        escrow.maker = maker;
-        escrow.mint_a = mint_a;
-        escrow.mint_b = mint_b;
-        escrow.amount = amount;
-        escrow.bump = bump;
+        *escrow = state;
+    }
+
+    /// Whether `taker` may fill the offer. Open offers can be taken by anyone.
+    pub fn can_take(&self, taker: &Pubkey) -> bool {
+        self.taker == [0; 32] || self.taker == *taker
+    }
+
+    /// Whether the offer can no longer be taken. An expiry of zero never expires.
+    pub fn is_expired(&self, now: i64) -> bool {
+        let expiry = i64::from_le_bytes(self.expiry);
+        expiry != 0 && now >= expiry
+    }
+}
+
+// v1 escrows have no header, their size is what gives them away
+fn check_len(len: usize) -> Result<(), ProgramError> {
+    if len == EscrowV1::LEN {
+        return Err(MyProgramError::UnsupportedVersion.into());
     }
+    Ok(())
+}
+
+/// The escrow layout before the discriminator and version were added. v1
+/// escrows sit at the unseeded `[b"escrow", maker]` address and hold their
+/// deposit in a token account the maker picked, so `Migrate` can't upgrade
+/// them in place and refunds them instead.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct EscrowV1 {
+    pub maker: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub amount: [u8; 8],
+    pub bump: u8,
+}
+
+impl DataLen for EscrowV1 {
+    const LEN: usize = core::mem::size_of::<EscrowV1>();
 }
@@ -1,6 +1,11 @@
-use pinocchio::program_error::ProgramError;
+use bytemuck::Pod;
+use pinocchio::{
+    account_info::{AccountInfo, Ref, RefMut},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
 
-use crate::error::MyProgramError;
+use crate::{error::MyProgramError, validation::AccountCheck};
 
 pub trait DataLen {
     const LEN: usize;
@@ -16,7 +21,7 @@ pub fn load_acc<T: DataLen + Initialized>(bytes: &[u8]) -> Result<&T, ProgramErr
         if acc.is_initialized() {
             Ok(acc)
         } else {
-            Err(ProgramError::UninitializedAccount)
+            Err(MyProgramError::InvalidDiscriminator.into())
         }
     })
 }
@@ -35,7 +40,7 @@ pub fn load_acc_mut<T: DataLen + Initialized>(bytes: &mut [u8]) -> Result<&mut T
         if acc.is_initialized() {
             Ok(acc)
         } else {
-            Err(ProgramError::UninitializedAccount)
+            Err(MyProgramError::InvalidDiscriminator.into())
         }
     })
 }
@@ -48,18 +53,41 @@ pub fn load_acc_mut_unchecked<T: DataLen>(bytes: &mut [u8]) -> Result<&mut T, Pr
     Ok(unsafe { &mut *(bytes.as_mut_ptr() as *mut T) })
 }
 
+/// Borrows an initialized account owned by `owner`. The account stays
+/// borrowed until the returned `Ref` is dropped.
 #[inline(always)]
-pub fn load_ix_data<T: DataLen>(bytes: &[u8]) -> Result<&T, ProgramError> {
-    if bytes.len() != T::LEN {
-        return Err(MyProgramError::InvalidInstructionData.into());
-    }
-    Ok(unsafe { &*(bytes.as_ptr() as *const T) })
+pub fn load_account<'a, T: DataLen + Initialized>(
+    account: &'a AccountInfo,
+    owner: &Pubkey,
+) -> Result<Ref<'a, T>, ProgramError> {
+    account.check_owner(owner)?;
+    let data = account.try_borrow_data()?;
+    load_acc::<T>(&data)?;
+    Ok(Ref::map(data, |data| unsafe {
+        &*(data.as_ptr() as *const T)
+    }))
 }
 
-pub fn to_bytes<T: DataLen>(data: &T) -> &[u8] {
-    unsafe { core::slice::from_raw_parts(data as *const T as *const u8, T::LEN) }
+/// Mutable counterpart of [`load_account`].
+#[inline(always)]
+pub fn load_account_mut<'a, T: DataLen + Initialized>(
+    account: &'a AccountInfo,
+    owner: &Pubkey,
+) -> Result<RefMut<'a, T>, ProgramError> {
+    account.check_owner(owner)?;
+    let mut data = account.try_borrow_mut_data()?;
+    load_acc_mut::<T>(&mut data)?;
+    Ok(RefMut::map(data, |data| unsafe {
+        &mut *(data.as_mut_ptr() as *mut T)
+    }))
 }
 
-pub fn to_mut_bytes<T: DataLen>(data: &mut T) -> &mut [u8] {
-    unsafe { core::slice::from_raw_parts_mut(data as *mut T as *mut u8, T::LEN) }
+/// Reads instruction data of exactly `T::LEN` bytes, trailing bytes included
+/// are rejected. Copied out since instruction data carries no alignment.
+#[inline(always)]
+pub fn load_ix_data<T: DataLen + Pod>(bytes: &[u8]) -> Result<T, ProgramError> {
+    if bytes.len() != T::LEN {
+        return Err(MyProgramError::InvalidInstructionData.into());
+    }
+    Ok(bytemuck::pod_read_unaligned(bytes))
 }
@@ -1,28 +1,37 @@
-#[cfg(test)]
+// the tests build their instructions with the std-only client
+#[cfg(all(test, feature = "std"))]
+#[allow(clippy::module_inception)]
 mod tests {
-
-    #![no_std]
     extern crate alloc;
 
     use alloc::vec;
-    use alloc::vec::Vec;
+    use core::mem::offset_of;
     use mollusk_svm::{program, result::Check, Mollusk};
     use pinocchio_log::log;
     use solana_sdk::{
-        account::{Account, AccountSharedData, WritableAccount},
+        account::{Account, WritableAccount},
         instruction::{AccountMeta, Instruction},
         native_token::LAMPORTS_PER_SOL,
+        program_error::ProgramError,
         program_option::COption,
         program_pack::Pack,
         pubkey,
         pubkey::Pubkey,
-        rent::Rent,
-        sysvar::Sysvar,
     };
     use spl_token::state::AccountState;
 
+    use crate::{
+        client,
+        error::MyProgramError,
+        instruction::{protocol_fee, release_amount, MakeEscrowIx},
+        state::{Config, DataLen, Escrow, EscrowV1},
+        token::TransferFee,
+    };
+
     const ID: Pubkey = pubkey!("A24MN2mj3aBpDLRhY6FonnbTuayv7oRqhva2R2hUuyqx");
     const DELEGATION_ACCOUNT: Pubkey = pubkey!("DELeGGvXpWV2fqJUhqcF5ZSYMS4JTLjteaAMARRSaeSh");
+    const TOKEN_2022_PROGRAM_ID: Pubkey = pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+    const SEED: u64 = 42;
 
     #[test]
     fn test_make() {
@@ -43,12 +52,9 @@ mod tests {
         );
 
         let maker = Pubkey::new_from_array([0x02; 32]);
-        let maker_account = Account::new(1 * LAMPORTS_PER_SOL, 0, &system_program);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
 
-        let (escrow, escrow_bump) = solana_sdk::pubkey::Pubkey::find_program_address(
-            &[(b"escrow"), &maker.to_bytes()],
-            &ID,
-        );
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
         log!("bump test {}", escrow_bump);
         let escrow_account = Account::new(0, 0, &system_program);
 
@@ -118,99 +124,287 @@ mod tests {
         )
         .unwrap();
 
-        let vault = Pubkey::new_from_array([0x06; 32]);
-        let mut vault_account = Account::new(
-            mollusk
-                .sysvars
-                .rent
-                .minimum_balance(spl_token::state::Account::LEN),
-            spl_token::state::Account::LEN,
-            &token_program,
+        // make creates the vault itself
+        let (vault, _) = client::vault_pda(&escrow);
+        let vault_account = Account::new(0, 0, &system_program);
+
+        let instruction = client::Make {
+            maker,
+            mint_a: mint_x,
+            mint_b: mint_y,
+            maker_ata_a: maker_ata,
+            token_program,
+            seed: SEED,
+            amount_a: 1_000_000,
+            amount_b: 1_000_000,
+            expiry: 0,
+            bounty: 0,
+            taker: None,
+        }
+        .instruction();
+
+        let accounts = [
+            (maker, maker_account),
+            (mint_x, mint_x_account),
+            (mint_y, mint_y_account),
+            (maker_ata, maker_ata_account),
+            (vault, vault_account),
+            (escrow, escrow_account),
+            (system_program, system_account),
+            (token_program, token_account),
+        ];
+
+        // only the canonical bump is accepted
+        let mut bad_bump = instruction.clone();
+        bad_bump.data[1] = escrow_bump.wrapping_sub(1);
+        mollusk.process_and_validate_instruction(
+            &bad_bump,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::EscrowPdaMismatch as u32,
+            ))],
         );
-        solana_sdk::program_pack::Pack::pack(
-            spl_token::state::Account {
-                mint: mint_x,
-                owner: escrow,
-                amount: 0,
-                delegate: COption::None,
-                state: AccountState::Initialized,
-                is_native: COption::None,
-                delegated_amount: 0,
-                close_authority: COption::None,
-            },
-            vault_account.data_as_mut_slice(),
-        )
-        .unwrap();
 
-        let data = [
-            vec![0],
-            vec![escrow_bump],
-            1_000_000u64.to_le_bytes().to_vec(),
-            1_000_000u64.to_le_bytes().to_vec(),
-        ]
-        .concat();
+        // an offer has to ask for something in return
+        let mut free = instruction.clone();
+        let amount_b = 1 + offset_of!(MakeEscrowIx, amount_b);
+        free.data[amount_b..amount_b + 8].fill(0);
+        mollusk.process_and_validate_instruction(
+            &free,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidAmount as u32,
+            ))],
+        );
 
-        let instruction = Instruction::new_with_bytes(
-            ID,
-            &data,
-            vec![
-                AccountMeta::new(maker, true),
-                AccountMeta::new_readonly(mint_x, false),
-                AccountMeta::new_readonly(mint_y, false),
-                AccountMeta::new(maker_ata, false),
-                AccountMeta::new(vault, false),
-                AccountMeta::new(escrow, true),
-                AccountMeta::new_readonly(system_program, false),
-                AccountMeta::new_readonly(token_program, false),
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&vault)
+                    .owner(&token_program)
+                    .space(spl_token::state::Account::LEN)
+                    .build(),
             ],
         );
 
+        // lamports sent to the PDAs beforehand don't block the make
+        let mut accounts = accounts;
+        accounts[4].1.lamports = 1;
+        accounts[5].1.lamports = 1;
         mollusk.process_and_validate_instruction(
             &instruction,
-            &vec![
-                (maker, maker_account),
-                (mint_x, mint_x_account),
-                (mint_y, mint_y_account),
-                (maker_ata, maker_ata_account),
-                (vault, vault_account),
-                (escrow, escrow_account),
-                (system_program, system_account),
-                (token_program, token_account),
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&escrow)
+                    .owner(&ID)
+                    .lamports(mollusk.sysvars.rent.minimum_balance(Escrow::LEN))
+                    .space(Escrow::LEN)
+                    .build(),
+                Check::account(&vault)
+                    .owner(&token_program)
+                    .space(spl_token::state::Account::LEN)
+                    .build(),
             ],
-            &[Check::success()],
         );
     }
 
     #[test]
-    fn test_take() {
-        //TEST unfinished
+    fn test_make_token_2022() {
         let mut mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
 
         let (system_program, system_account) =
             mollusk_svm::program::keyed_account_for_system_program();
 
         mollusk.add_program(
-            &spl_token::ID,
-            "src/tests/spl_token-3.5.0",
+            &TOKEN_2022_PROGRAM_ID,
+            "src/tests/spl_token_2022-8.0.1",
             &mollusk_svm::program::loader_keys::LOADER_V3,
         );
 
-        let (token_program, token_account) = (
-            spl_token::ID,
-            program::create_program_account_loader_v3(&spl_token::ID),
+        let (token_program, token_account_program) = (
+            TOKEN_2022_PROGRAM_ID,
+            program::create_program_account_loader_v3(&TOKEN_2022_PROGRAM_ID),
         );
 
         let maker = Pubkey::new_from_array([0x02; 32]);
-        let maker_account = Account::new(1 * LAMPORTS_PER_SOL, 0, &system_program);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        let (escrow, _) = client::escrow_pda(&maker, SEED);
+        let escrow_account = Account::new(0, 0, &system_program);
 
+        // mint_a lives under Token-2022, mint_b under the legacy program
         let mint_x = Pubkey::new_from_array([0x03; 32]);
-        let mut mint_x_account = Account::new(
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
+        let maker_ata = Pubkey::new_from_array([0x05; 32]);
+        let (vault, _) = client::vault_pda(&escrow);
+
+        let instruction = client::Make {
+            maker,
+            mint_a: mint_x,
+            mint_b: mint_y,
+            maker_ata_a: maker_ata,
+            token_program,
+            seed: SEED,
+            amount_a: 1_000_000,
+            amount_b: 1_000_000,
+            expiry: 0,
+            bounty: 0,
+            taker: None,
+        }
+        .instruction();
+
+        let result = mollusk.process_and_validate_instruction(
+            &instruction,
+            &[
+                (maker, maker_account),
+                (mint_x, mint_account(&mollusk, &token_program)),
+                (mint_y, mint_account(&mollusk, &spl_token::ID)),
+                (
+                    maker_ata,
+                    token_account(&mollusk, &token_program, mint_x, maker, 100_000_000),
+                ),
+                (vault, Account::new(0, 0, &system_program)),
+                (escrow, escrow_account),
+                (system_program, system_account),
+                (token_program, token_account_program),
+            ],
+            &[Check::success()],
+        );
+
+        let vault_state =
+            spl_token::state::Account::unpack(&result.get_account(&vault).unwrap().data).unwrap();
+        assert_eq!(vault_state.amount, 1_000_000);
+    }
+
+    #[test]
+    fn test_transfer_fee() {
+        let transfer_fee = TransferFee {
+            epoch: 0,
+            maximum_fee: 5_000,
+            basis_points: 100,
+        };
+
+        // 1% rounded up, capped at the maximum fee
+        assert_eq!(transfer_fee.fee(1_001), Some(11));
+        assert_eq!(transfer_fee.fee(10_000_000), Some(5_000));
+
+        // the gross amount always nets out to exactly what the maker asked for
+        for net in [1, 99, 1_000, 123_456, 10_000_000] {
+            let gross = transfer_fee.pre_fee_amount(net).unwrap();
+            assert_eq!(gross - transfer_fee.fee(gross).unwrap(), net);
+        }
+    }
+
+    #[test]
+    fn test_make_non_transferable_mint() {
+        let mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let (system_program, system_account) =
+            mollusk_svm::program::keyed_account_for_system_program();
+
+        let (token_program, token_account_program) = (
+            TOKEN_2022_PROGRAM_ID,
+            program::create_program_account_loader_v3(&TOKEN_2022_PROGRAM_ID),
+        );
+
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        let (escrow, _) = client::escrow_pda(&maker, SEED);
+
+        // base mint, padding, account type, then an empty NonTransferable entry
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let mut mint_x_account = mint_account(&mollusk, &token_program);
+        mint_x_account
+            .data
+            .resize(spl_token::state::Account::LEN, 0);
+        mint_x_account.data.push(1);
+        mint_x_account.data.extend_from_slice(&9u16.to_le_bytes());
+        mint_x_account.data.extend_from_slice(&0u16.to_le_bytes());
+
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
+        let maker_ata = Pubkey::new_from_array([0x05; 32]);
+        let (vault, _) = client::vault_pda(&escrow);
+
+        let instruction = client::Make {
+            maker,
+            mint_a: mint_x,
+            mint_b: mint_y,
+            maker_ata_a: maker_ata,
+            token_program,
+            seed: SEED,
+            amount_a: 1_000_000,
+            amount_b: 1_000_000,
+            expiry: 0,
+            bounty: 0,
+            taker: None,
+        }
+        .instruction();
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &[
+                (maker, maker_account),
+                (mint_x, mint_x_account),
+                (mint_y, mint_account(&mollusk, &spl_token::ID)),
+                (
+                    maker_ata,
+                    token_account(&mollusk, &token_program, mint_x, maker, 100_000_000),
+                ),
+                (vault, Account::new(0, 0, &system_program)),
+                (escrow, Account::new(0, 0, &system_program)),
+                (system_program, system_account),
+                (token_program, token_account_program),
+            ],
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::NonTransferableMint as u32,
+            ))],
+        );
+    }
+
+    fn token_account(
+        mollusk: &Mollusk,
+        token_program: &Pubkey,
+        mint: Pubkey,
+        owner: Pubkey,
+        amount: u64,
+    ) -> Account {
+        let mut account = Account::new(
+            mollusk
+                .sysvars
+                .rent
+                .minimum_balance(spl_token::state::Account::LEN),
+            spl_token::state::Account::LEN,
+            token_program,
+        );
+        solana_sdk::program_pack::Pack::pack(
+            spl_token::state::Account {
+                mint,
+                owner,
+                amount,
+                delegate: COption::None,
+                state: AccountState::Initialized,
+                is_native: COption::None,
+                delegated_amount: 0,
+                close_authority: COption::None,
+            },
+            account.data_as_mut_slice(),
+        )
+        .unwrap();
+        account
+    }
+
+    fn mint_account(mollusk: &Mollusk, token_program: &Pubkey) -> Account {
+        let mut account = Account::new(
             mollusk
                 .sysvars
                 .rent
                 .minimum_balance(spl_token::state::Mint::LEN),
             spl_token::state::Mint::LEN,
-            &token_program,
+            token_program,
         );
         solana_sdk::program_pack::Pack::pack(
             spl_token::state::Mint {
@@ -220,139 +414,1735 @@ mod tests {
                 is_initialized: true,
                 freeze_authority: COption::None,
             },
-            mint_x_account.data_as_mut_slice(),
+            account.data_as_mut_slice(),
         )
         .unwrap();
+        account
+    }
+
+    // a Token-2022 mint charging `basis_points`, capped at `maximum_fee`
+    fn fee_mint_account(mollusk: &Mollusk, basis_points: u16, maximum_fee: u64) -> Account {
+        let mut data = mint_account(mollusk, &TOKEN_2022_PROGRAM_ID).data;
+        data.resize(spl_token::state::Account::LEN, 0);
+        data.push(1);
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&108u16.to_le_bytes());
+        // no authorities, nothing withheld, the same fee in both epochs
+        data.extend_from_slice(&[0; 72]);
+        for _ in 0..2 {
+            data.extend_from_slice(&0u64.to_le_bytes());
+            data.extend_from_slice(&maximum_fee.to_le_bytes());
+            data.extend_from_slice(&basis_points.to_le_bytes());
+        }
+
+        let mut account = Account::new(
+            mollusk.sysvars.rent.minimum_balance(data.len()),
+            data.len(),
+            &TOKEN_2022_PROGRAM_ID,
+        );
+        account.data_as_mut_slice().copy_from_slice(&data);
+        account
+    }
+
+    // a token account of a fee mint, with an empty TransferFeeAmount entry
+    fn fee_token_account(mollusk: &Mollusk, mint: Pubkey, owner: Pubkey, amount: u64) -> Account {
+        let mut data = token_account(mollusk, &TOKEN_2022_PROGRAM_ID, mint, owner, amount).data;
+        data.push(2);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&8u16.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+
+        let mut account = Account::new(
+            mollusk.sysvars.rent.minimum_balance(data.len()),
+            data.len(),
+            &TOKEN_2022_PROGRAM_ID,
+        );
+        account.data_as_mut_slice().copy_from_slice(&data);
+        account
+    }
+
+    // carries the accounts an instruction wrote over to the next one
+    fn update_accounts(accounts: &mut Vec<(Pubkey, Account)>, resulting: &[(Pubkey, Account)]) {
+        for (key, account) in resulting {
+            match accounts.iter_mut().find(|(k, _)| k == key) {
+                Some(entry) => entry.1 = account.clone(),
+                None => accounts.push((*key, account.clone())),
+            }
+        }
+    }
+
+    // the program's ProgramData account, upgradeable by `authority`
+    fn program_data_account(mollusk: &Mollusk, authority: Pubkey) -> (Pubkey, Account) {
+        let (program_data, _) = client::program_data_pda();
+        let data = [
+            3u32.to_le_bytes().to_vec(),
+            0u64.to_le_bytes().to_vec(),
+            vec![1],
+            authority.to_bytes().to_vec(),
+        ]
+        .concat();
+        let mut account = Account::new(
+            mollusk.sysvars.rent.minimum_balance(data.len()),
+            data.len(),
+            &client::BPF_LOADER_UPGRADEABLE_ID,
+        );
+        account.data_as_mut_slice().copy_from_slice(&data);
+        (program_data, account)
+    }
+
+    fn escrow_account(mollusk: &Mollusk, escrow: Escrow) -> Account {
+        let data = bytemuck::bytes_of(&escrow);
+        let mut account = Account::new(
+            mollusk.sysvars.rent.minimum_balance(data.len()),
+            data.len(),
+            &ID,
+        );
+        account.data_as_mut_slice().copy_from_slice(data);
+        account
+    }
+
+    fn config_account(mollusk: &Mollusk, fee_recipient: Pubkey, fee_bps: u16) -> (Pubkey, Account) {
+        let (config, config_bump) = Pubkey::find_program_address(&[b"config"], &ID);
+        let data = bytemuck::bytes_of(&Config {
+            discriminator: Config::DISCRIMINATOR,
+            admin: [0x01; 32],
+            fee_recipient: fee_recipient.to_bytes(),
+            fee_bps: fee_bps.to_le_bytes(),
+            bump: config_bump,
+        })
+        .to_vec();
+        let mut account = Account::new(
+            mollusk.sysvars.rent.minimum_balance(data.len()),
+            data.len(),
+            &ID,
+        );
+        account.data_as_mut_slice().copy_from_slice(&data);
+        (config, account)
     }
 
     #[test]
-    fn test_delegate() {
+    fn test_protocol_fee() {
+        // 1% of the payment
+        assert_eq!(protocol_fee(500_000, 100), 5_000);
+        // rounded down, dust fills pay nothing
+        assert_eq!(protocol_fee(199, 50), 0);
+        assert_eq!(protocol_fee(201, 50), 1);
+        assert_eq!(protocol_fee(u64::MAX, 0), 0);
+    }
+
+    #[test]
+    fn test_release_amount() {
+        // pro-rata, rounded down in favour of the vault
+        assert_eq!(release_amount(1_000, 3_000, 1_000), Some(333));
+        // the closing fill takes whatever is left
+        assert_eq!(release_amount(334, 1_000, 1_000), Some(334));
+        // nothing to pay, overpaying and dust fills are rejected
+        assert_eq!(release_amount(1_000, 3_000, 0), None);
+        assert_eq!(release_amount(1_000, 3_000, 3_001), None);
+        assert_eq!(release_amount(1, 3_000, 1), None);
+    }
+
+    #[test]
+    fn test_take() {
         let mut mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
 
         let (system_program, system_account) =
             mollusk_svm::program::keyed_account_for_system_program();
 
-        // Setup maker account
-        let maker = Pubkey::new_from_array([0x02; 32]);
-        let maker_account = Account::new(1 * LAMPORTS_PER_SOL, 0, &system_program);
+        mollusk.add_program(
+            &spl_token::ID,
+            "src/tests/spl_token-3.5.0",
+            &mollusk_svm::program::loader_keys::LOADER_V3,
+        );
 
-        // Setup PDA account
-        let (escrow, escrow_bump) = solana_sdk::pubkey::Pubkey::find_program_address(
-            &[(b"escrow"), &maker.to_bytes()],
-            &ID,
+        let (token_program, token_account_program) = (
+            spl_token::ID,
+            program::create_program_account_loader_v3(&spl_token::ID),
         );
-        let mut escrow_account = Account::new(mollusk.sysvars.rent.minimum_balance(100), 100, &ID);
-        // Add some test data to escrow
-        escrow_account.data_mut()[0] = 42;
 
-        // Setup buffer account
-        let (buffer, buffer_bump) =
-            solana_sdk::pubkey::Pubkey::find_program_address(&[(b"buffer"), escrow.as_ref()], &ID);
-        let buffer_account = Account::new(0, 0, &system_program);
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
 
-        // Setup delegation accounts
-        let delegation_record = Pubkey::new_from_array([0x07; 32]);
-        let delegation_record_account = Account::new(0, 0, &system_program);
+        let taker = Pubkey::new_from_array([0x07; 32]);
+        let taker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
 
-        let delegation_metadata = Pubkey::new_from_array([0x08; 32]);
-        let delegation_metadata_account = Account::new(0, 0, &system_program);
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
 
-        let magic_program = ID;
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
 
-        let instruction = Instruction::new_with_bytes(
-            ID,
-            &[1], // Delegate instruction
-            vec![
-                AccountMeta::new(maker, true),
-                AccountMeta::new(escrow, false),
-                AccountMeta::new_readonly(magic_program, false),
-                AccountMeta::new(buffer, false),
-                AccountMeta::new(delegation_record, false),
-                AccountMeta::new_readonly(delegation_metadata, false),
-                AccountMeta::new_readonly(system_program, false),
-            ],
+        let taker_ata_x = Pubkey::new_from_array([0x08; 32]);
+        let taker_ata_y = Pubkey::new_from_array([0x09; 32]);
+        let maker_ata_y = Pubkey::new_from_array([0x0a; 32]);
+        let (vault, _) = client::vault_pda(&escrow);
+
+        // the protocol keeps 1% of every payment
+        let fee_recipient = Pubkey::new_from_array([0x0d; 32]);
+        let fee_ata_y = Pubkey::new_from_array([0x0e; 32]);
+        let (config, config_account) = config_account(&mollusk, fee_recipient, 100);
+
+        // fill a quarter of a 1_000_000 X for 2_000_000 Y offer
+        let instruction = client::Take {
+            taker,
+            maker,
+            seed: SEED,
+            mint_a: mint_x,
+            mint_a_transfer_fee: false,
+            mint_b: mint_y,
+            taker_ata_a: taker_ata_x,
+            taker_ata_b: taker_ata_y,
+            maker_ata_b: maker_ata_y,
+            token_program_a: token_program,
+            token_program_b: token_program,
+            fee_recipient_b: fee_ata_y,
+            amount: 500_000,
+        }
+        .instruction();
+
+        let mut accounts = vec![
+            (taker, taker_account),
+            (maker, maker_account),
+            (mint_x, mint_account(&mollusk, &token_program)),
+            (mint_y, mint_account(&mollusk, &token_program)),
+            (
+                taker_ata_x,
+                token_account(&mollusk, &token_program, mint_x, taker, 0),
+            ),
+            (
+                taker_ata_y,
+                token_account(&mollusk, &token_program, mint_y, taker, 2_000_000),
+            ),
+            (
+                maker_ata_y,
+                token_account(&mollusk, &token_program, mint_y, maker, 0),
+            ),
+            (
+                vault,
+                token_account(&mollusk, &token_program, mint_x, escrow, 1_000_000),
+            ),
+            (
+                escrow,
+                escrow_account(
+                    &mollusk,
+                    Escrow {
+                        discriminator: Escrow::DISCRIMINATOR,
+                        version: Escrow::VERSION,
+                        maker: maker.to_bytes(),
+                        seed: SEED.to_le_bytes(),
+                        mint_a: mint_x.to_bytes(),
+                        mint_b: mint_y.to_bytes(),
+                        taker: taker.to_bytes(),
+                        amount: 2_000_000u64.to_le_bytes(),
+                        deposit: 1_000_000u64.to_le_bytes(),
+                        expiry: [0; 8],
+                        bounty: [0; 8],
+                        native_a: 0,
+                        native_b: 0,
+                        bump: escrow_bump,
+                    },
+                ),
+            ),
+            (token_program, token_account_program),
+            (system_program, system_account),
+            (config, config_account),
+            (
+                fee_ata_y,
+                token_account(&mollusk, &token_program, mint_y, fee_recipient, 0),
+            ),
+        ];
+
+        let result =
+            mollusk.process_and_validate_instruction(&instruction, &accounts, &[Check::success()]);
+
+        // the offer stays open with the remaining three quarters
+        let vault_state =
+            spl_token::state::Account::unpack(&result.get_account(&vault).unwrap().data).unwrap();
+        assert_eq!(vault_state.amount, 750_000);
+
+        // the payment is split between the maker and the fee recipient
+        let maker_state =
+            spl_token::state::Account::unpack(&result.get_account(&maker_ata_y).unwrap().data)
+                .unwrap();
+        assert_eq!(maker_state.amount, 495_000);
+        let fee_state =
+            spl_token::state::Account::unpack(&result.get_account(&fee_ata_y).unwrap().data)
+                .unwrap();
+        assert_eq!(fee_state.amount, 5_000);
+
+        let escrow_data = &result.get_account(&escrow).unwrap().data;
+        assert_eq!(
+            escrow_data[offset_of!(Escrow, amount)..][..8],
+            1_500_000u64.to_le_bytes()
+        );
+        assert_eq!(
+            escrow_data[offset_of!(Escrow, deposit)..][..8],
+            750_000u64.to_le_bytes()
         );
 
+        // the offer names its taker, nobody else can fill it
+        let stranger = Pubkey::new_from_array([0x0c; 32]);
+        let mut instruction = instruction;
+        instruction.accounts[0].pubkey = stranger;
+        accounts[0].0 = stranger;
         mollusk.process_and_validate_instruction(
             &instruction,
-            &vec![
-                (maker, maker_account.clone()),
-                (escrow, escrow_account),
-                (magic_program, Account::default()),
-                (buffer, buffer_account),
-                (delegation_record, delegation_record_account),
-                (delegation_metadata, delegation_metadata_account),
-                (system_program, system_account.clone()),
-            ],
-            &[Check::success()],
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidTaker as u32,
+            ))],
+        );
+
+        // a vault the escrow does not hold is rejected before anything moves
+        instruction.accounts[0].pubkey = taker;
+        accounts[0].0 = taker;
+        accounts[7].1 = token_account(&mollusk, &token_program, mint_x, maker, 1_000_000);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::VaultOwnerMismatch as u32,
+            ))],
+        );
+
+        // the taker can't route the maker's payment back to itself
+        accounts[7].1 = token_account(&mollusk, &token_program, mint_x, escrow, 1_000_000);
+        accounts[6].1 = token_account(&mollusk, &token_program, mint_y, taker, 0);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidAccount as u32,
+            ))],
         );
     }
 
     #[test]
-    fn test_undelegate() {
+    fn test_take_native_b() {
         let mut mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
 
         let (system_program, system_account) =
             mollusk_svm::program::keyed_account_for_system_program();
 
-        // Setup maker account
+        mollusk.add_program(
+            &spl_token::ID,
+            "src/tests/spl_token-3.5.0",
+            &mollusk_svm::program::loader_keys::LOADER_V3,
+        );
+
+        let (token_program, token_account_program) = (
+            spl_token::ID,
+            program::create_program_account_loader_v3(&spl_token::ID),
+        );
+
         let maker = Pubkey::new_from_array([0x02; 32]);
-        let maker_account = Account::new(1 * LAMPORTS_PER_SOL, 0, &system_program);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
 
-        // Setup delegated PDA account
-        let (escrow, escrow_bump) = solana_sdk::pubkey::Pubkey::find_program_address(
-            &[(b"escrow"), &maker.to_bytes()],
-            &ID,
+        let taker = Pubkey::new_from_array([0x07; 32]);
+        let taker_account = Account::new(2 * LAMPORTS_PER_SOL, 0, &system_program);
+
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
+
+        // X for native SOL, the SOL leg has no token accounts
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let native_mint = spl_token::native_mint::ID;
+        let taker_ata_x = Pubkey::new_from_array([0x08; 32]);
+        let unused_a = Pubkey::new_from_array([0x09; 32]);
+        let unused_b = Pubkey::new_from_array([0x0a; 32]);
+        let (vault, _) = client::vault_pda(&escrow);
+
+        // no protocol fee, the fee account is never touched
+        let unused_fee = Pubkey::new_from_array([0x0d; 32]);
+        let (config, config_account) = config_account(&mollusk, unused_fee, 0);
+
+        // half of the SOL asked for releases half of the vault
+        let instruction = client::Take {
+            taker,
+            maker,
+            seed: SEED,
+            mint_a: mint_x,
+            mint_a_transfer_fee: false,
+            mint_b: native_mint,
+            taker_ata_a: taker_ata_x,
+            taker_ata_b: unused_a,
+            maker_ata_b: unused_b,
+            token_program_a: token_program,
+            token_program_b: token_program,
+            fee_recipient_b: unused_fee,
+            amount: LAMPORTS_PER_SOL / 2,
+        }
+        .instruction();
+
+        let mut accounts = vec![
+            (taker, taker_account),
+            (maker, maker_account),
+            (mint_x, mint_account(&mollusk, &token_program)),
+            (native_mint, mint_account(&mollusk, &token_program)),
+            (
+                taker_ata_x,
+                token_account(&mollusk, &token_program, mint_x, taker, 0),
+            ),
+            (unused_a, Account::default()),
+            (unused_b, Account::default()),
+            (
+                vault,
+                token_account(&mollusk, &token_program, mint_x, escrow, 1_000_000),
+            ),
+            (
+                escrow,
+                escrow_account(
+                    &mollusk,
+                    Escrow {
+                        discriminator: Escrow::DISCRIMINATOR,
+                        version: Escrow::VERSION,
+                        maker: maker.to_bytes(),
+                        seed: SEED.to_le_bytes(),
+                        mint_a: mint_x.to_bytes(),
+                        mint_b: native_mint.to_bytes(),
+                        taker: [0; 32],
+                        amount: LAMPORTS_PER_SOL.to_le_bytes(),
+                        deposit: 1_000_000u64.to_le_bytes(),
+                        expiry: [0; 8],
+                        bounty: [0; 8],
+                        native_a: 0,
+                        native_b: 1,
+                        bump: escrow_bump,
+                    },
+                ),
+            ),
+            (token_program, token_account_program),
+            (system_program, system_account),
+            (config, config_account),
+            (unused_fee, Account::default()),
+        ];
+        let checks = [
+            Check::success(),
+            Check::account(&maker)
+                .lamports(LAMPORTS_PER_SOL + LAMPORTS_PER_SOL / 2)
+                .build(),
+            Check::account(&taker)
+                .lamports(2 * LAMPORTS_PER_SOL - LAMPORTS_PER_SOL / 2)
+                .build(),
+        ];
+        mollusk.process_and_validate_instruction(&instruction, &accounts, &checks);
+
+        // before InitConfig has run fills are free
+        accounts[11].1 = Account::default();
+        mollusk.process_and_validate_instruction(&instruction, &accounts, &checks);
+
+        // but only the config's address stands in for it
+        let mut wrong_config = instruction.clone();
+        wrong_config.accounts[12].pubkey = unused_a;
+        mollusk.process_and_validate_instruction(
+            &wrong_config,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::PdaMismatch as u32,
+            ))],
         );
-        let escrow_account = Account::new(
-            mollusk.sysvars.rent.minimum_balance(100),
-            100,
-            &DELEGATION_ACCOUNT, // Note: owned by delegation program
+    }
+
+    #[test]
+    fn test_transfer_fee_vault() {
+        let mut mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let (system_program, system_account) =
+            mollusk_svm::program::keyed_account_for_system_program();
+
+        mollusk.add_program(
+            &spl_token::ID,
+            "src/tests/spl_token-3.5.0",
+            &mollusk_svm::program::loader_keys::LOADER_V3,
+        );
+        mollusk.add_program(
+            &TOKEN_2022_PROGRAM_ID,
+            "src/tests/spl_token_2022-8.0.1",
+            &mollusk_svm::program::loader_keys::LOADER_V3,
         );
 
-        // Setup buffer account with stored data
-        let (buffer, buffer_bump) =
-            solana_sdk::pubkey::Pubkey::find_program_address(&[(b"buffer"), escrow.as_ref()], &ID);
-        let mut buffer_account = Account::new(mollusk.sysvars.rent.minimum_balance(100), 100, &ID);
-        // Add original data to buffer
-        buffer_account.data_mut()[0] = 42;
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let taker = Pubkey::new_from_array([0x07; 32]);
 
-        // Setup delegation accounts
-        let delegation_record = Pubkey::new_from_array([0x07; 32]);
-        let delegation_record_account = Account::new(0, 0, &system_program);
+        // mint_a withholds 1% of every transfer, mint_b is a legacy mint
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
+        let maker_ata_x = Pubkey::new_from_array([0x05; 32]);
+        let taker_ata_x = Pubkey::new_from_array([0x08; 32]);
+        let taker_ata_y = Pubkey::new_from_array([0x09; 32]);
+        let maker_ata_y = Pubkey::new_from_array([0x0a; 32]);
+        let fee_recipient = Pubkey::new_from_array([0x0d; 32]);
+        let (_, fee_config_account) = config_account(&mollusk, fee_recipient, 100);
+        let (config, config_account) = config_account(&mollusk, fee_recipient, 0);
 
-        let delegation_metadata = Pubkey::new_from_array([0x08; 32]);
-        let delegation_metadata_account = Account::new(0, 0, &system_program);
+        let mut accounts = vec![
+            (maker, Account::new(LAMPORTS_PER_SOL, 0, &system_program)),
+            (taker, Account::new(LAMPORTS_PER_SOL, 0, &system_program)),
+            (mint_x, fee_mint_account(&mollusk, 100, 1_000_000)),
+            (mint_y, mint_account(&mollusk, &spl_token::ID)),
+            (
+                maker_ata_x,
+                fee_token_account(&mollusk, mint_x, maker, 100_000_000),
+            ),
+            (taker_ata_x, fee_token_account(&mollusk, mint_x, taker, 0)),
+            (
+                taker_ata_y,
+                token_account(&mollusk, &spl_token::ID, mint_y, taker, 2_000_000),
+            ),
+            (
+                maker_ata_y,
+                token_account(&mollusk, &spl_token::ID, mint_y, maker, 0),
+            ),
+            (config, config_account),
+            (system_program, system_account),
+            (
+                TOKEN_2022_PROGRAM_ID,
+                program::create_program_account_loader_v3(&TOKEN_2022_PROGRAM_ID),
+            ),
+            (
+                spl_token::ID,
+                program::create_program_account_loader_v3(&spl_token::ID),
+            ),
+        ];
 
-        let instruction = Instruction::new_with_bytes(
-            ID,
-            &[2], // Undelegate instruction
-            vec![
-                AccountMeta::new(maker, true),
-                AccountMeta::new(escrow, false),
-                AccountMeta::new(buffer, false),
-                AccountMeta::new(delegation_record, false),
-                AccountMeta::new_readonly(delegation_metadata, false),
-                AccountMeta::new_readonly(system_program, false),
-            ],
-        );
+        let make = |seed: u64| {
+            let (escrow, _) = client::escrow_pda(&maker, seed);
+            let (vault, _) = client::vault_pda(&escrow);
+            let instruction = client::Make {
+                maker,
+                mint_a: mint_x,
+                mint_b: mint_y,
+                maker_ata_a: maker_ata_x,
+                token_program: TOKEN_2022_PROGRAM_ID,
+                seed,
+                amount_a: 1_000_000,
+                amount_b: 1_000_000,
+                expiry: 0,
+                bounty: 0,
+                taker: None,
+            }
+            .instruction();
+            (escrow, vault, instruction)
+        };
 
-        mollusk.process_and_validate_instruction(
+        // the vault is left holding the fee withheld on the deposit, the escrow
+        // records what actually arrived
+        let (escrow, vault, instruction) = make(SEED);
+        accounts.push((escrow, Account::new(0, 0, &system_program)));
+        accounts.push((vault, Account::new(0, 0, &system_program)));
+        let result = mollusk.process_and_validate_instruction(
             &instruction,
-            &vec![
-                (maker, maker_account.clone()),
-                (escrow, escrow_account),
-                (magic_program, Account::default()),
-                (buffer, buffer_account),
-                (delegation_record, delegation_record_account),
-                (delegation_metadata, delegation_metadata_account),
-                (system_program, system_account.clone()),
-            ],
+            &accounts,
             &[
                 Check::success(),
-                Check::account_owner(escrow, &DELEGATION_ACCOUNT),
-                Check::account_data_len(buffer, 100),
+                Check::account(&escrow)
+                    .data_slice(offset_of!(Escrow, deposit), &990_000u64.to_le_bytes())
+                    .build(),
+                Check::account(&vault)
+                    .data_slice(64, &990_000u64.to_le_bytes())
+                    .build(),
             ],
         );
+        update_accounts(&mut accounts, &result.resulting_accounts);
+
+        // the closing fill harvests the withheld fee to the mint and closes the vault
+        let instruction = client::Take {
+            taker,
+            maker,
+            seed: SEED,
+            mint_a: mint_x,
+            mint_a_transfer_fee: true,
+            mint_b: mint_y,
+            taker_ata_a: taker_ata_x,
+            taker_ata_b: taker_ata_y,
+            maker_ata_b: maker_ata_y,
+            token_program_a: TOKEN_2022_PROGRAM_ID,
+            token_program_b: spl_token::ID,
+            fee_recipient_b: fee_recipient,
+            amount: 1_000_000,
+        }
+        .instruction();
+        accounts.push((fee_recipient, Account::new(0, 0, &system_program)));
+        let result = mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&vault).closed().build(),
+                Check::account(&escrow).closed().build(),
+            ],
+        );
+        update_accounts(&mut accounts, &result.resulting_accounts);
+
+        // the maker's refund of a fee mint offer closes its vault the same way
+        let (escrow, vault, instruction) = make(SEED + 1);
+        accounts.push((escrow, Account::new(0, 0, &system_program)));
+        accounts.push((vault, Account::new(0, 0, &system_program)));
+        let result =
+            mollusk.process_and_validate_instruction(&instruction, &accounts, &[Check::success()]);
+        update_accounts(&mut accounts, &result.resulting_accounts);
+
+        let instruction = client::Refund {
+            maker,
+            seed: SEED + 1,
+            mint_a: mint_x,
+            mint_a_transfer_fee: true,
+            maker_ata_a: maker_ata_x,
+            token_program: TOKEN_2022_PROGRAM_ID,
+            cranker: None,
+        }
+        .instruction();
+        let result = mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&vault).closed().build(),
+                Check::account(&escrow).closed().build(),
+            ],
+        );
+        update_accounts(&mut accounts, &result.resulting_accounts);
+
+        // mint_b withholds 2%, the taker pays it on top so the maker and the
+        // fee recipient each receive exactly their share
+        let mint_z = Pubkey::new_from_array([0x0e; 32]);
+        let taker_ata_z = Pubkey::new_from_array([0x0f; 32]);
+        let maker_ata_z = Pubkey::new_from_array([0x10; 32]);
+        let fee_ata_z = Pubkey::new_from_array([0x11; 32]);
+        accounts.extend([
+            (mint_z, fee_mint_account(&mollusk, 200, 1_000_000)),
+            (
+                taker_ata_z,
+                fee_token_account(&mollusk, mint_z, taker, 2_000_000),
+            ),
+            (maker_ata_z, fee_token_account(&mollusk, mint_z, maker, 0)),
+            (
+                fee_ata_z,
+                fee_token_account(&mollusk, mint_z, fee_recipient, 0),
+            ),
+        ]);
+        // a 1% protocol fee
+        let config_entry = accounts.iter_mut().find(|(k, _)| *k == config).unwrap();
+        config_entry.1 = fee_config_account;
+
+        let (escrow, _) = client::escrow_pda(&maker, SEED + 2);
+        let (vault, _) = client::vault_pda(&escrow);
+        let instruction = client::Make {
+            maker,
+            mint_a: mint_x,
+            mint_b: mint_z,
+            maker_ata_a: maker_ata_x,
+            token_program: TOKEN_2022_PROGRAM_ID,
+            seed: SEED + 2,
+            amount_a: 1_000_000,
+            amount_b: 1_000_000,
+            expiry: 0,
+            bounty: 0,
+            taker: None,
+        }
+        .instruction();
+        accounts.push((escrow, Account::new(0, 0, &system_program)));
+        accounts.push((vault, Account::new(0, 0, &system_program)));
+        let result =
+            mollusk.process_and_validate_instruction(&instruction, &accounts, &[Check::success()]);
+        update_accounts(&mut accounts, &result.resulting_accounts);
+
+        let instruction = client::Take {
+            taker,
+            maker,
+            seed: SEED + 2,
+            mint_a: mint_x,
+            mint_a_transfer_fee: true,
+            mint_b: mint_z,
+            taker_ata_a: taker_ata_x,
+            taker_ata_b: taker_ata_z,
+            maker_ata_b: maker_ata_z,
+            token_program_a: TOKEN_2022_PROGRAM_ID,
+            token_program_b: TOKEN_2022_PROGRAM_ID,
+            fee_recipient_b: fee_ata_z,
+            amount: 1_000_000,
+        }
+        .instruction();
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&maker_ata_z)
+                    .data_slice(64, &990_000u64.to_le_bytes())
+                    .build(),
+                Check::account(&fee_ata_z)
+                    .data_slice(64, &10_000u64.to_le_bytes())
+                    .build(),
+                Check::account(&escrow).closed().build(),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_refund_expired() {
+        let mut mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+        mollusk.sysvars.clock.unix_timestamp = 1_000;
+
+        let (system_program, system_account) =
+            mollusk_svm::program::keyed_account_for_system_program();
+
+        mollusk.add_program(
+            &spl_token::ID,
+            "src/tests/spl_token-3.5.0",
+            &mollusk_svm::program::loader_keys::LOADER_V3,
+        );
+
+        let (token_program, token_account_program) = (
+            spl_token::ID,
+            program::create_program_account_loader_v3(&spl_token::ID),
+        );
+
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        let cranker = Pubkey::new_from_array([0x0b; 32]);
+        let cranker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
+
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
+        let maker_ata_x = Pubkey::new_from_array([0x05; 32]);
+        let (vault, _) = client::vault_pda(&escrow);
+
+        // the maker does not sign, a third party cranks the expired offer
+        let instruction = client::Refund {
+            maker,
+            seed: SEED,
+            mint_a: mint_x,
+            mint_a_transfer_fee: false,
+            maker_ata_a: maker_ata_x,
+            token_program,
+            cranker: Some(cranker),
+        }
+        .instruction();
+
+        let mut accounts = vec![
+            (maker, maker_account),
+            (mint_x, mint_account(&mollusk, &token_program)),
+            (
+                maker_ata_x,
+                token_account(&mollusk, &token_program, mint_x, maker, 0),
+            ),
+            (
+                vault,
+                token_account(&mollusk, &token_program, mint_x, escrow, 1_000_000),
+            ),
+            (
+                escrow,
+                escrow_account(
+                    &mollusk,
+                    Escrow {
+                        discriminator: Escrow::DISCRIMINATOR,
+                        version: Escrow::VERSION,
+                        maker: maker.to_bytes(),
+                        seed: SEED.to_le_bytes(),
+                        mint_a: mint_x.to_bytes(),
+                        mint_b: mint_y.to_bytes(),
+                        taker: [0; 32],
+                        amount: 2_000_000u64.to_le_bytes(),
+                        deposit: 1_000_000u64.to_le_bytes(),
+                        expiry: 500i64.to_le_bytes(),
+                        bounty: 10_000u64.to_le_bytes(),
+                        native_a: 0,
+                        native_b: 0,
+                        bump: escrow_bump,
+                    },
+                ),
+            ),
+            (token_program, token_account_program),
+            (system_program, system_account),
+            (cranker, cranker_account),
+        ];
+
+        // a cranker can't redirect the deposit to an account of its own
+        accounts[2].1 = token_account(&mollusk, &token_program, mint_x, cranker, 0);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidAccount as u32,
+            ))],
+        );
+
+        accounts[2].1 = token_account(&mollusk, &token_program, mint_x, maker, 0);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&cranker)
+                    .lamports(LAMPORTS_PER_SOL + 10_000)
+                    .build(),
+                Check::account(&escrow).lamports(0).build(),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_amend() {
+        let mut mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let (system_program, system_account) =
+            mollusk_svm::program::keyed_account_for_system_program();
+
+        mollusk.add_program(
+            &spl_token::ID,
+            "src/tests/spl_token-3.5.0",
+            &mollusk_svm::program::loader_keys::LOADER_V3,
+        );
+
+        let (token_program, token_account_program) = (
+            spl_token::ID,
+            program::create_program_account_loader_v3(&spl_token::ID),
+        );
+
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
+
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
+        let maker_ata_x = Pubkey::new_from_array([0x05; 32]);
+        let (vault, _) = client::vault_pda(&escrow);
+
+        // reprice to 3_000_000 Y and pull 400_000 X back out of the vault
+        let instruction = client::Amend {
+            maker,
+            seed: SEED,
+            mint_a: mint_x,
+            maker_ata_a: maker_ata_x,
+            token_program,
+            amount: 3_000_000,
+            top_up: 0,
+            withdraw: 400_000,
+        }
+        .instruction();
+
+        let mut accounts = vec![
+            (maker, maker_account),
+            (mint_x, mint_account(&mollusk, &token_program)),
+            (
+                maker_ata_x,
+                token_account(&mollusk, &token_program, mint_x, maker, 0),
+            ),
+            (
+                vault,
+                token_account(&mollusk, &token_program, mint_x, escrow, 1_000_000),
+            ),
+            (
+                escrow,
+                escrow_account(
+                    &mollusk,
+                    Escrow {
+                        discriminator: Escrow::DISCRIMINATOR,
+                        version: Escrow::VERSION,
+                        maker: maker.to_bytes(),
+                        seed: SEED.to_le_bytes(),
+                        mint_a: mint_x.to_bytes(),
+                        mint_b: mint_y.to_bytes(),
+                        taker: [0; 32],
+                        amount: 2_000_000u64.to_le_bytes(),
+                        deposit: 1_000_000u64.to_le_bytes(),
+                        expiry: [0; 8],
+                        bounty: [0; 8],
+                        native_a: 0,
+                        native_b: 0,
+                        bump: escrow_bump,
+                    },
+                ),
+            ),
+            (token_program, token_account_program),
+            (system_program, system_account),
+        ];
+
+        let result =
+            mollusk.process_and_validate_instruction(&instruction, &accounts, &[Check::success()]);
+
+        let maker_state =
+            spl_token::state::Account::unpack(&result.get_account(&maker_ata_x).unwrap().data)
+                .unwrap();
+        assert_eq!(maker_state.amount, 400_000);
+
+        let escrow_data = &result.get_account(&escrow).unwrap().data;
+        assert_eq!(
+            escrow_data[offset_of!(Escrow, amount)..][..8],
+            3_000_000u64.to_le_bytes()
+        );
+        assert_eq!(
+            escrow_data[offset_of!(Escrow, deposit)..][..8],
+            600_000u64.to_le_bytes()
+        );
+
+        // a delegated escrow belongs to the rollup until it is undelegated
+        accounts[4].1.owner = DELEGATION_ACCOUNT;
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::EscrowDelegated as u32,
+            ))],
+        );
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        mollusk.add_program(
+            &spl_token::ID,
+            "src/tests/spl_token-3.5.0",
+            &mollusk_svm::program::loader_keys::LOADER_V3,
+        );
+        let (token_program, token_program_account) = (
+            spl_token::ID,
+            program::create_program_account_loader_v3(&spl_token::ID),
+        );
+
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &solana_sdk::system_program::ID);
+
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
+
+        let (escrow, escrow_bump) = client::escrow_v1_pda(&maker);
+
+        let v1 = EscrowV1 {
+            maker: maker.to_bytes(),
+            mint_a: mint_x.to_bytes(),
+            mint_b: mint_y.to_bytes(),
+            amount: 2_000_000u64.to_le_bytes(),
+            bump: escrow_bump,
+        };
+        let escrow_rent = mollusk.sysvars.rent.minimum_balance(EscrowV1::LEN);
+        let mut v1_account = Account::new(escrow_rent, EscrowV1::LEN, &ID);
+        v1_account
+            .data_as_mut_slice()
+            .copy_from_slice(bytemuck::bytes_of(&v1));
+
+        // v1 vaults were plain token accounts, not PDAs
+        let vault = Pubkey::new_from_array([0x05; 32]);
+        let vault_account = token_account(&mollusk, &token_program, mint_x, escrow, 1_000_000);
+        let vault_rent = vault_account.lamports;
+
+        let maker_ata_a = Pubkey::new_from_array([0x06; 32]);
+        let maker_ata_a_account = token_account(&mollusk, &token_program, mint_x, maker, 0);
+
+        let instruction = client::Migrate {
+            maker,
+            mint_a: mint_x,
+            maker_ata_a,
+            vault,
+            token_program,
+        }
+        .instruction();
+
+        let mut accounts = vec![
+            (maker, maker_account),
+            (mint_x, mint_account(&mollusk, &token_program)),
+            (maker_ata_a, maker_ata_a_account),
+            (vault, vault_account),
+            (escrow, v1_account),
+            (token_program, token_program_account),
+        ];
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&maker)
+                    .lamports(LAMPORTS_PER_SOL + escrow_rent + vault_rent)
+                    .build(),
+                Check::account(&maker_ata_a)
+                    .data_slice(64, &1_000_000u64.to_le_bytes())
+                    .build(),
+                Check::account(&vault).closed().build(),
+                Check::account(&escrow).closed().build(),
+            ],
+        );
+
+        // only the maker can retire their escrow
+        let mut unsigned = instruction.clone();
+        unsigned.accounts[0].is_signer = false;
+        mollusk.process_and_validate_instruction(
+            &unsigned,
+            &accounts,
+            &[Check::err(ProgramError::MissingRequiredSignature)],
+        );
+
+        // the vault has to belong to the escrow
+        accounts[3].1 = token_account(&mollusk, &token_program, mint_x, maker, 1_000_000);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::VaultOwnerMismatch as u32,
+            ))],
+        );
+
+        // escrows already on the current layout are left alone
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
+        accounts[4] = (
+            escrow,
+            escrow_account(
+                &mollusk,
+                Escrow {
+                    discriminator: Escrow::DISCRIMINATOR,
+                    version: Escrow::VERSION,
+                    maker: maker.to_bytes(),
+                    seed: SEED.to_le_bytes(),
+                    mint_a: mint_x.to_bytes(),
+                    mint_b: mint_y.to_bytes(),
+                    taker: [0; 32],
+                    amount: 2_000_000u64.to_le_bytes(),
+                    deposit: 1_000_000u64.to_le_bytes(),
+                    expiry: [0; 8],
+                    bounty: [0; 8],
+                    native_a: 0,
+                    native_b: 0,
+                    bump: escrow_bump,
+                },
+            ),
+        );
+        let mut instruction = instruction;
+        instruction.accounts[4].pubkey = escrow;
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::AccountAlreadyInitialized)],
+        );
+    }
+
+    #[test]
+    fn test_decode_event() {
+        use crate::event::{
+            decode::{DecodeError, EscrowEvent},
+            AmendEvent, TakeEvent, EVENT_VERSION,
+        };
+
+        let event = TakeEvent {
+            escrow: [0x07; 32],
+            taker: [0x08; 32],
+            paid: 1_000_000u64.to_le_bytes(),
+            fee: 10_000u64.to_le_bytes(),
+            released: 500_000u64.to_le_bytes(),
+            remaining_a: 0u64.to_le_bytes(),
+            remaining_b: 0u64.to_le_bytes(),
+        };
+        let body = bytemuck::bytes_of(&event);
+
+        assert_eq!(
+            EscrowEvent::decode(&[&[EVENT_VERSION, 1], body]),
+            Ok(EscrowEvent::Take(event))
+        );
+        assert_eq!(
+            EscrowEvent::decode(&[&[EVENT_VERSION + 1, 1], body]),
+            Err(DecodeError::UnsupportedVersion(EVENT_VERSION + 1))
+        );
+        assert_eq!(
+            EscrowEvent::decode(&[&[EVENT_VERSION, 9], body]),
+            Err(DecodeError::UnknownKind(9))
+        );
+        // a take body is not a refund
+        assert_eq!(
+            EscrowEvent::decode(&[&[EVENT_VERSION, 2], body]),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            EscrowEvent::decode(&[body]),
+            Err(DecodeError::InvalidFields)
+        );
+
+        // amend and migrate come after the delegation events
+        let event = AmendEvent {
+            escrow: [0x07; 32],
+            maker: [0x02; 32],
+            received: 0u64.to_le_bytes(),
+            withdrawn: 250_000u64.to_le_bytes(),
+            deposit: 750_000u64.to_le_bytes(),
+            amount: 3_000_000u64.to_le_bytes(),
+        };
+        assert_eq!(
+            EscrowEvent::decode(&[&[EVENT_VERSION, 5], bytemuck::bytes_of(&event)]),
+            Ok(EscrowEvent::Amend(event))
+        );
+    }
+
+    #[test]
+    fn test_decode_escrow_account() {
+        use client::{EscrowAccount, EscrowState, Memcmp};
+
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let mint_x = Pubkey::new_from_array([0x03; 32]);
+        let mint_y = Pubkey::new_from_array([0x04; 32]);
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
+
+        let state = Escrow {
+            discriminator: Escrow::DISCRIMINATOR,
+            version: Escrow::VERSION,
+            maker: maker.to_bytes(),
+            seed: SEED.to_le_bytes(),
+            mint_a: mint_x.to_bytes(),
+            mint_b: mint_y.to_bytes(),
+            taker: [0; 32],
+            amount: 2_000_000u64.to_le_bytes(),
+            deposit: 1_000_000u64.to_le_bytes(),
+            expiry: 500i64.to_le_bytes(),
+            bounty: 10_000u64.to_le_bytes(),
+            native_a: 0,
+            native_b: 1,
+            bump: escrow_bump,
+        };
+        let data = bytemuck::bytes_of(&state);
+        assert_eq!(data.len(), client::ESCROW_LEN);
+
+        let decoded = EscrowAccount::decode(data).unwrap();
+        assert_eq!(
+            decoded,
+            EscrowAccount {
+                maker,
+                seed: SEED,
+                mint_a: mint_x,
+                mint_b: mint_y,
+                taker: None,
+                amount: 2_000_000,
+                deposit: 1_000_000,
+                expiry: Some(500),
+                bounty: 10_000,
+                native_a: false,
+                native_b: true,
+                bump: escrow_bump,
+            }
+        );
+        assert_eq!(decoded.address(), escrow);
+        assert_eq!(decoded.state(499), EscrowState::Open);
+        assert_eq!(decoded.state(500), EscrowState::Expired);
+
+        // the filters match the bytes the program writes
+        for filter in [
+            Memcmp::escrow(),
+            Memcmp::maker(&maker),
+            Memcmp::mint_a(&mint_x),
+            Memcmp::mint_b(&mint_y),
+        ] {
+            assert_eq!(
+                data[filter.offset..filter.offset + filter.bytes.len()],
+                filter.bytes[..]
+            );
+        }
+        assert_ne!(
+            Memcmp::mint_a(&mint_y).bytes,
+            data[client::MINT_A_OFFSET..][..32]
+        );
+
+        // a pre-migration escrow has to be migrated first
+        assert_eq!(
+            EscrowAccount::decode(&[0; EscrowV1::LEN]),
+            Err(pinocchio::program_error::ProgramError::Custom(
+                MyProgramError::UnsupportedVersion as u32
+            ))
+        );
+
+        // zeroed data of the right size isn't an escrow
+        assert_eq!(
+            EscrowAccount::decode(&[0; client::ESCROW_LEN]),
+            Err(pinocchio::program_error::ProgramError::Custom(
+                MyProgramError::InvalidDiscriminator as u32
+            ))
+        );
+    }
+
+    #[test]
+    fn test_error_codes() {
+        // codes are stable, clients match on them
+        assert_eq!(MyProgramError::WriteOverflow as u32, 0);
+        assert_eq!(MyProgramError::InvalidTaker as u32, 11);
+        assert_eq!(MyProgramError::NotSigner as u32, 26);
+
+        for (code, error) in MyProgramError::ALL.into_iter().enumerate() {
+            assert_eq!(error as u32, code as u32);
+            assert_eq!(MyProgramError::try_from(code as u32), Ok(error));
+        }
+        let unknown = MyProgramError::ALL.len() as u32;
+        assert_eq!(MyProgramError::try_from(unknown), Err(unknown));
+
+        assert_eq!(
+            MyProgramError::EscrowExpired.to_string(),
+            "escrow has expired (error 9)"
+        );
+    }
+
+    #[test]
+    fn test_instruction_discriminators() {
+        use crate::instruction::MyProgramInstrution;
+
+        for (i, ix) in MyProgramInstrution::ALL.into_iter().enumerate() {
+            let disc = ix as u8;
+            assert_eq!(disc as usize, i);
+            assert!(!MyProgramInstrution::RESERVED.contains(&disc));
+            assert_eq!(MyProgramInstrution::try_from(&disc), Ok(ix));
+        }
+
+        // unassigned and reserved bytes are rejected
+        for disc in MyProgramInstrution::ALL.len() as u8..=u8::MAX {
+            assert!(MyProgramInstrution::try_from(&disc).is_err());
+        }
+
+        // delegate and undelegate used to share a discriminator
+        assert_ne!(
+            MyProgramInstrution::Delegate as u8,
+            MyProgramInstrution::UnDelegate as u8
+        );
+
+        // the delegation program's callback can't collide with an instruction
+        assert!(MyProgramInstrution::RESERVED
+            .contains(&crate::instruction::UNDELEGATE_DISCRIMINATOR[0]));
+    }
+
+    #[test]
+    fn test_load_ix_data() {
+        use crate::{
+            instruction::{MakeEscrowIx, TakeEscrowIx},
+            state::load_ix_data,
+        };
+
+        let data = 500_000u64.to_le_bytes();
+        let ix_data = load_ix_data::<TakeEscrowIx>(&data).unwrap();
+        assert_eq!(u64::from_le_bytes(ix_data.amount), 500_000);
+
+        // short and trailing bytes are both rejected
+        let mut long = data.to_vec();
+        long.push(0);
+        assert!(load_ix_data::<TakeEscrowIx>(&long).is_err());
+        assert!(load_ix_data::<TakeEscrowIx>(&data[..7]).is_err());
+
+        // instruction data has no alignment guarantee
+        let make = [0u8; MakeEscrowIx::LEN + 1];
+        assert!(load_ix_data::<MakeEscrowIx>(&make[1..]).is_ok());
+    }
+
+    #[test]
+    fn test_pda_seeds() {
+        use crate::instruction::PdaSeeds;
+
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let mut data = borsh::to_vec(&client::escrow_seeds(&maker, SEED)).unwrap();
+        data.extend_from_slice(&[7, 7]);
+
+        // the seeds derive the escrow, what follows them is handed back
+        let (seeds, rest) = PdaSeeds::parse(&data).unwrap();
+        assert_eq!(rest, &[7, 7]);
+        assert_eq!(
+            Pubkey::find_program_address(seeds.as_slice(), &ID),
+            client::escrow_pda(&maker, SEED)
+        );
+
+        // a seed slot is kept for the bump and seeds are capped at 32 bytes
+        let too_many = borsh::to_vec(&vec![vec![0u8; 1]; 16]).unwrap();
+        assert!(PdaSeeds::parse(&too_many).is_err());
+        let too_long = borsh::to_vec(&vec![vec![0u8; 33]]).unwrap();
+        assert!(PdaSeeds::parse(&too_long).is_err());
+        assert!(PdaSeeds::parse(&data[..data.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn test_init_config() {
+        let mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let (system_program, system_account) =
+            mollusk_svm::program::keyed_account_for_system_program();
+
+        let admin = Pubkey::new_from_array([0x01; 32]);
+        let admin_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        let (config, config_bump) = Pubkey::find_program_address(&[b"config"], &ID);
+        let fee_recipient = Pubkey::new_from_array([0x0d; 32]);
+        let (program_data, program_data_account) = program_data_account(&mollusk, admin);
+
+        let instruction = client::InitConfig {
+            admin,
+            fee_recipient,
+            fee_bps: 100,
+        }
+        .instruction();
+
+        let expected = Config {
+            discriminator: Config::DISCRIMINATOR,
+            admin: admin.to_bytes(),
+            fee_recipient: fee_recipient.to_bytes(),
+            fee_bps: 100u16.to_le_bytes(),
+            bump: config_bump,
+        };
+
+        let mut accounts = vec![
+            (admin, admin_account),
+            (config, Account::new(0, 0, &system_program)),
+            (system_program, system_account),
+            (program_data, program_data_account),
+        ];
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&config)
+                    .owner(&ID)
+                    .data(bytemuck::bytes_of(&expected))
+                    .build(),
+            ],
+        );
+
+        // fees above the cap are rejected
+        let instruction = client::InitConfig {
+            admin,
+            fee_recipient,
+            fee_bps: 1_001,
+        }
+        .instruction();
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidFeeBps as u32,
+            ))],
+        );
+
+        // only the upgrade authority can create the config
+        let stranger = Pubkey::new_from_array([0x0c; 32]);
+        let instruction = client::InitConfig {
+            admin: stranger,
+            fee_recipient,
+            fee_bps: 100,
+        }
+        .instruction();
+        accounts[0].0 = stranger;
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidAdmin as u32,
+            ))],
+        );
+    }
+
+    #[test]
+    fn test_update_config() {
+        let mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let admin = Pubkey::new_from_array([0x01; 32]);
+        let new_admin = Pubkey::new_from_array([0x0c; 32]);
+        let fee_recipient = Pubkey::new_from_array([0x0d; 32]);
+        let (config, config_account) = config_account(&mollusk, fee_recipient, 100);
+        let config_bump = config_account.data[Config::LEN - 1];
+
+        // the admin hands the config over and halves the fee
+        let instruction = client::UpdateConfig {
+            admin,
+            new_admin,
+            fee_recipient,
+            fee_bps: 50,
+        }
+        .instruction();
+
+        let mut accounts = vec![(admin, Account::default()), (config, config_account)];
+
+        let expected = Config {
+            discriminator: Config::DISCRIMINATOR,
+            admin: new_admin.to_bytes(),
+            fee_recipient: fee_recipient.to_bytes(),
+            fee_bps: 50u16.to_le_bytes(),
+            bump: config_bump,
+        };
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&config)
+                    .data(bytemuck::bytes_of(&expected))
+                    .build(),
+            ],
+        );
+
+        // nobody else can
+        let instruction = client::UpdateConfig {
+            admin: new_admin,
+            new_admin,
+            fee_recipient,
+            fee_bps: 50,
+        }
+        .instruction();
+        accounts[0].0 = new_admin;
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidAdmin as u32,
+            ))],
+        );
+    }
+
+    #[test]
+    fn test_delegate() {
+        let mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let (system_program, system_account) =
+            mollusk_svm::program::keyed_account_for_system_program();
+
+        // Setup maker account
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let maker_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        // Setup PDA account
+        let (escrow, _) = client::escrow_pda(&maker, SEED);
+        let mut escrow_account = Account::new(mollusk.sysvars.rent.minimum_balance(100), 100, &ID);
+        // Add some test data to escrow
+        escrow_account.data_as_mut_slice()[0] = 42;
+
+        // Setup buffer account
+        let (buffer, _) = client::buffer_pda(&escrow);
+        let buffer_account = Account::new(0, 0, &system_program);
+
+        // Setup delegation accounts
+        let (delegation_record, _) = client::delegation_record_pda(&escrow);
+        let delegation_record_account = Account::new(0, 0, &system_program);
+
+        let (delegation_metadata, _) = client::delegation_metadata_pda(&escrow);
+        let delegation_metadata_account = Account::new(0, 0, &system_program);
+
+        // the delegation program expects the owner program as the third account
+        let magic_program = ID;
+
+        let accounts = vec![
+            (maker, maker_account.clone()),
+            (escrow, escrow_account),
+            (magic_program, Account::default()),
+            (buffer, buffer_account),
+            (delegation_record, delegation_record_account),
+            (delegation_metadata, delegation_metadata_account),
+            (system_program, system_account.clone()),
+        ];
+
+        // commits faster than once a second are rejected
+        let instruction = client::Delegate {
+            payer: maker,
+            seeds: client::escrow_seeds(&maker, SEED),
+            commit_frequency_ms: 500,
+            validator: None,
+        }
+        .instruction();
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidCommitFrequency as u32,
+            ))],
+        );
+
+        // only a party named in the seeds may delegate the account
+        let stranger = Pubkey::new_from_array([0x0d; 32]);
+        let mut instruction = client::Delegate {
+            payer: maker,
+            seeds: client::escrow_seeds(&maker, SEED),
+            commit_frequency_ms: client::DEFAULT_COMMIT_FREQUENCY_MS,
+            validator: None,
+        }
+        .instruction();
+        instruction.accounts[0] = AccountMeta::new(stranger, true);
+        let mut stranger_accounts = accounts.clone();
+        stranger_accounts[0] = (stranger, maker_account.clone());
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &stranger_accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidDelegationAuthority as u32,
+            ))],
+        );
+
+        // the seeds have to derive the delegated account
+        let mut instruction = client::Delegate {
+            payer: maker,
+            seeds: client::escrow_seeds(&maker, SEED + 1),
+            commit_frequency_ms: client::DEFAULT_COMMIT_FREQUENCY_MS,
+            validator: None,
+        }
+        .instruction();
+        instruction.accounts[1] = AccountMeta::new(escrow, false);
+        instruction.accounts[3] = AccountMeta::new(buffer, false);
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::PdaMismatch as u32,
+            ))],
+        );
+
+        // the delegation program isn't loaded, so the rest only covers the checks
+        // made before calling it. The config has no signer seed, its admin gets
+        // past the authority check and on to the commit frequency
+
+        let admin = Pubkey::new_from_array([0x01; 32]);
+        let (config, config_account) = config_account(&mollusk, maker, 0);
+        let (buffer, _) = client::buffer_pda(&config);
+        let (delegation_record, _) = client::delegation_record_pda(&config);
+        let (delegation_metadata, _) = client::delegation_metadata_pda(&config);
+        let instruction = client::Delegate {
+            payer: admin,
+            seeds: client::config_seeds(),
+            commit_frequency_ms: 500,
+            validator: None,
+        }
+        .instruction();
+        let mut accounts = vec![
+            (admin, maker_account.clone()),
+            (config, config_account),
+            (magic_program, Account::default()),
+            (buffer, Account::new(0, 0, &system_program)),
+            (delegation_record, Account::new(0, 0, &system_program)),
+            (delegation_metadata, Account::new(0, 0, &system_program)),
+            (system_program, system_account),
+        ];
+
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidCommitFrequency as u32,
+            ))],
+        );
+
+        // nobody else can
+        let mut instruction = instruction;
+        instruction.accounts[0] = AccountMeta::new(stranger, true);
+        accounts[0] = (stranger, maker_account);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidDelegationAuthority as u32,
+            ))],
+        );
+    }
+
+    #[test]
+    fn test_undelegate_callback() {
+        use crate::instruction::UNDELEGATE_DISCRIMINATOR;
+
+        let mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let (system_program, system_account) =
+            mollusk_svm::program::keyed_account_for_system_program();
+
+        let payer = Pubkey::new_from_array([0x0e; 32]);
+        let payer_account = Account::new(LAMPORTS_PER_SOL, 0, &system_program);
+
+        // the delegation program has closed the escrow by the time it calls back
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let (escrow, _) = client::escrow_pda(&maker, SEED);
+        let escrow_account = Account::new(0, 0, &system_program);
+
+        // its buffer holds the escrow's committed data
+        let buffer = Pubkey::new_from_array([0x0f; 32]);
+        let mut buffer_account = Account::new(
+            mollusk.sysvars.rent.minimum_balance(100),
+            100,
+            &DELEGATION_ACCOUNT,
+        );
+        buffer_account.data_as_mut_slice()[0] = 42;
+
+        let mut data = UNDELEGATE_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&borsh::to_vec(&client::escrow_seeds(&maker, SEED)).unwrap());
+        let instruction = Instruction::new_with_bytes(
+            ID,
+            &data,
+            vec![
+                AccountMeta::new(escrow, false),
+                AccountMeta::new_readonly(buffer, true),
+                AccountMeta::new(payer, true),
+                AccountMeta::new_readonly(system_program, false),
+            ],
+        );
+        let accounts = vec![
+            (escrow, escrow_account),
+            (buffer, buffer_account.clone()),
+            (payer, payer_account),
+            (system_program, system_account),
+        ];
+
+        let mut expected = vec![0; 100];
+        expected[0] = 42;
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[
+                Check::success(),
+                Check::account(&escrow).owner(&ID).data(&expected).build(),
+            ],
+        );
+
+        // only the delegation program can hand over a buffer
+        let mut forged = accounts.clone();
+        forged[1].1.owner = ID;
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &forged,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidOwner as u32,
+            ))],
+        );
+
+        let mut unsigned = instruction.clone();
+        unsigned.accounts[1] = AccountMeta::new_readonly(buffer, false);
+        mollusk.process_and_validate_instruction(
+            &unsigned,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::NotSigner as u32,
+            ))],
+        );
+
+        // the seeds have to derive the account being restored
+        let mut wrong_seeds = UNDELEGATE_DISCRIMINATOR.to_vec();
+        wrong_seeds
+            .extend_from_slice(&borsh::to_vec(&client::escrow_seeds(&maker, SEED + 1)).unwrap());
+        let mut instruction = instruction;
+        instruction.data = wrong_seeds;
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::PdaMismatch as u32,
+            ))],
+        );
+    }
+
+    #[test]
+    fn test_commit() {
+        let mollusk = Mollusk::new(&ID, "target/deploy/pinocchio_3");
+
+        let payer = Pubkey::new_from_array([0x0e; 32]);
+        let payer_account = Account::new(LAMPORTS_PER_SOL, 0, &Pubkey::default());
+
+        let maker = Pubkey::new_from_array([0x02; 32]);
+        let (escrow, escrow_bump) = client::escrow_pda(&maker, SEED);
+        let state = Escrow {
+            discriminator: Escrow::DISCRIMINATOR,
+            version: Escrow::VERSION,
+            maker: maker.to_bytes(),
+            seed: SEED.to_le_bytes(),
+            mint_a: [0x03; 32],
+            mint_b: [0x04; 32],
+            taker: [0; 32],
+            amount: 2_000_000u64.to_le_bytes(),
+            deposit: 1_000_000u64.to_le_bytes(),
+            expiry: 0i64.to_le_bytes(),
+            bounty: 0u64.to_le_bytes(),
+            native_a: 0,
+            native_b: 0,
+            bump: escrow_bump,
+        };
+
+        let instruction = client::Commit {
+            payer,
+            seeds: client::escrow_seeds(&maker, SEED),
+        }
+        .instruction();
+        let accounts = vec![
+            (payer, payer_account),
+            (escrow, escrow_account(&mollusk, state)),
+            (
+                client::MAGIC_CONTEXT_ID,
+                Account::new(0, 0, &client::MAGIC_PROGRAM_ID),
+            ),
+            (
+                client::MAGIC_PROGRAM_ID,
+                program::create_program_account_loader_v3(&client::MAGIC_PROGRAM_ID),
+            ),
+        ];
+
+        // on the base layer a delegated escrow belongs to the delegation program
+        let mut delegated = accounts.clone();
+        delegated[1].1.owner = DELEGATION_ACCOUNT;
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &delegated,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::EscrowDelegated as u32,
+            ))],
+        );
+
+        // the commit has to be scheduled on the magic context
+        let mut wrong_context = instruction.clone();
+        wrong_context.accounts[2] = AccountMeta::new(payer, false);
+        mollusk.process_and_validate_instruction(
+            &wrong_context,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidAccount as u32,
+            ))],
+        );
+
+        // the seeds have to derive the committed account
+        let wrong_seeds = client::Commit {
+            payer,
+            seeds: client::escrow_seeds(&maker, SEED + 1),
+        }
+        .instruction();
+        let mut wrong_accounts = accounts.clone();
+        wrong_accounts[1].0 = wrong_seeds.accounts[1].pubkey;
+        mollusk.process_and_validate_instruction(
+            &wrong_seeds,
+            &wrong_accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::PdaMismatch as u32,
+            ))],
+        );
+
+        // only the maker can end the delegation
+        let mut instruction = client::CommitAndUndelegate {
+            authority: maker,
+            seeds: client::escrow_seeds(&maker, SEED),
+        }
+        .instruction();
+        instruction.accounts[0] = AccountMeta::new(payer, true);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidDelegationAuthority as u32,
+            ))],
+        );
+
+        // nor, for the config, anyone but its admin
+        let (config, config_account) = config_account(&mollusk, maker, 0);
+        let mut instruction = client::CommitAndUndelegate {
+            authority: payer,
+            seeds: client::config_seeds(),
+        }
+        .instruction();
+        instruction.accounts[0] = AccountMeta::new(payer, true);
+        let mut config_accounts = accounts.clone();
+        config_accounts[1] = (config, config_account);
+        mollusk.process_and_validate_instruction(
+            &instruction,
+            &config_accounts,
+            &[Check::err(ProgramError::Custom(
+                MyProgramError::InvalidDelegationAuthority as u32,
+            ))],
+        );
     }
 }
@@ -0,0 +1,435 @@
+use pinocchio::{
+    account_info::AccountInfo,
+    cpi::invoke_signed,
+    instruction::{AccountMeta, Instruction, Signer},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    ProgramResult,
+};
+
+use crate::{error::MyProgramError, validation::AccountCheck};
+
+pub const TOKEN_PROGRAM_ID: Pubkey = pinocchio_token::ID;
+
+pub const TOKEN_2022_PROGRAM_ID: Pubkey =
+    pinocchio_pubkey::pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+/// Legs using the native mint are settled in lamports rather than wrapped SOL.
+pub const NATIVE_MINT: Pubkey =
+    pinocchio_pubkey::pubkey!("So11111111111111111111111111111111111111112");
+
+// Both programs share the base layouts, Token-2022 appends its extensions after them.
+pub const MINT_BASE_LEN: usize = 82;
+pub const TOKEN_ACCOUNT_BASE_LEN: usize = 165;
+
+// Token-2022 accounts of transfer-fee mints carry a TransferFeeAmount extension.
+const TRANSFER_FEE_ACCOUNT_LEN: usize = TOKEN_ACCOUNT_BASE_LEN + 1 + 4 + 8;
+
+const MINT_DECIMALS_OFFSET: usize = 44;
+const TOKEN_ACCOUNT_MINT_OFFSET: usize = 0;
+const TOKEN_ACCOUNT_OWNER_OFFSET: usize = 32;
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+
+pub fn is_native_mint(mint: &AccountInfo) -> bool {
+    mint.key() == &NATIVE_MINT
+}
+
+/// Returns the token program owning `account`, either SPL Token or Token-2022.
+pub fn token_program_of(account: &AccountInfo) -> Result<&Pubkey, ProgramError> {
+    let owner = unsafe { account.owner() };
+    if owner == &TOKEN_PROGRAM_ID || owner == &TOKEN_2022_PROGRAM_ID {
+        Ok(owner)
+    } else {
+        Err(MyProgramError::InvalidTokenProgram.into())
+    }
+}
+
+/// Checks that `token_program` is the program owning `mint`.
+pub fn check_token_program(mint: &AccountInfo, token_program: &AccountInfo) -> ProgramResult {
+    if token_program_of(mint)? != token_program.key() || !token_program.executable() {
+        return Err(MyProgramError::InvalidTokenProgram.into());
+    }
+    Ok(())
+}
+
+pub fn mint_decimals(mint: &AccountInfo) -> Result<u8, ProgramError> {
+    token_program_of(mint)?;
+    let data = mint.try_borrow_data()?;
+    if data.len() < MINT_BASE_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(data[MINT_DECIMALS_OFFSET])
+}
+
+pub fn token_account_mint(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+    token_program_of(account)?;
+    let data = account.try_borrow_data()?;
+    if data.len() < TOKEN_ACCOUNT_BASE_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    data[TOKEN_ACCOUNT_MINT_OFFSET..TOKEN_ACCOUNT_MINT_OFFSET + 32]
+        .try_into()
+        .map_err(|_| MyProgramError::DeserializationFailed.into())
+}
+
+pub fn token_account_owner(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+    token_program_of(account)?;
+    let data = account.try_borrow_data()?;
+    if data.len() < TOKEN_ACCOUNT_BASE_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    data[TOKEN_ACCOUNT_OWNER_OFFSET..TOKEN_ACCOUNT_OWNER_OFFSET + 32]
+        .try_into()
+        .map_err(|_| MyProgramError::DeserializationFailed.into())
+}
+
+pub fn token_account_amount(account: &AccountInfo) -> Result<u64, ProgramError> {
+    token_program_of(account)?;
+    let data = account.try_borrow_data()?;
+    if data.len() < TOKEN_ACCOUNT_BASE_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    data[TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8]
+        .try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|_| MyProgramError::DeserializationFailed.into())
+}
+
+/// Checks that `vault` is held by `escrow`: a token account owned by the escrow,
+/// or for native legs a lamport account owned by the program.
+pub fn check_vault_owner(vault: &AccountInfo, escrow: &AccountInfo, native: bool) -> ProgramResult {
+    let owned = if native {
+        (unsafe { vault.owner() }) == &crate::ID
+    } else {
+        token_account_owner(vault)? == *escrow.key()
+    };
+    if !owned {
+        return Err(MyProgramError::VaultOwnerMismatch.into());
+    }
+    Ok(())
+}
+
+/// Space needed for a vault of a mint we accept, see [`mint_transfer_fee`].
+pub fn vault_len(has_transfer_fee: bool) -> usize {
+    if has_transfer_fee {
+        TRANSFER_FEE_ACCOUNT_LEN
+    } else {
+        TOKEN_ACCOUNT_BASE_LEN
+    }
+}
+
+/// `TransferChecked` against whichever token program owns the mint.
+pub struct TransferChecked<'a> {
+    pub from: &'a AccountInfo,
+    pub mint: &'a AccountInfo,
+    pub to: &'a AccountInfo,
+    pub authority: &'a AccountInfo,
+    pub amount: u64,
+    pub decimals: u8,
+    pub token_program: &'a Pubkey,
+}
+
+impl TransferChecked<'_> {
+    #[inline(always)]
+    pub fn invoke(&self) -> ProgramResult {
+        self.invoke_signed(&[])
+    }
+
+    pub fn invoke_signed(&self, signers: &[Signer]) -> ProgramResult {
+        let account_metas = [
+            AccountMeta::writable(self.from.key()),
+            AccountMeta::readonly(self.mint.key()),
+            AccountMeta::writable(self.to.key()),
+            AccountMeta::readonly_signer(self.authority.key()),
+        ];
+
+        // discriminator, amount, decimals
+        let mut data = [0u8; 10];
+        data[0] = 12;
+        data[1..9].copy_from_slice(&self.amount.to_le_bytes());
+        data[9] = self.decimals;
+
+        let instruction = Instruction {
+            program_id: self.token_program,
+            accounts: &account_metas,
+            data: &data,
+        };
+
+        invoke_signed(
+            &instruction,
+            &[self.from, self.mint, self.to, self.authority],
+            signers,
+        )
+    }
+}
+
+/// `InitializeAccount3` against whichever token program owns the mint.
+pub struct InitializeAccount3<'a> {
+    pub account: &'a AccountInfo,
+    pub mint: &'a AccountInfo,
+    pub owner: &'a Pubkey,
+    pub token_program: &'a Pubkey,
+}
+
+impl InitializeAccount3<'_> {
+    pub fn invoke(&self) -> ProgramResult {
+        let account_metas = [
+            AccountMeta::writable(self.account.key()),
+            AccountMeta::readonly(self.mint.key()),
+        ];
+
+        // discriminator, owner
+        let mut data = [0u8; 33];
+        data[0] = 18;
+        data[1..33].copy_from_slice(self.owner);
+
+        let instruction = Instruction {
+            program_id: self.token_program,
+            accounts: &account_metas,
+            data: &data,
+        };
+
+        invoke_signed(&instruction, &[self.account, self.mint], &[])
+    }
+}
+
+/// `HarvestWithheldTokensToMint`, moves the transfer fees withheld in `account`
+/// to the mint so Token-2022 lets the account be closed.
+pub struct HarvestWithheldTokensToMint<'a> {
+    pub mint: &'a AccountInfo,
+    pub account: &'a AccountInfo,
+}
+
+impl HarvestWithheldTokensToMint<'_> {
+    pub fn invoke(&self) -> ProgramResult {
+        let account_metas = [
+            AccountMeta::writable(self.mint.key()),
+            AccountMeta::writable(self.account.key()),
+        ];
+
+        // transfer fee extension, harvest
+        let instruction = Instruction {
+            program_id: &TOKEN_2022_PROGRAM_ID,
+            accounts: &account_metas,
+            data: &[26, 4],
+        };
+
+        invoke_signed(&instruction, &[self.mint, self.account], &[])
+    }
+}
+
+/// `CloseAccount` against whichever token program owns the account.
+pub struct CloseAccount<'a> {
+    pub account: &'a AccountInfo,
+    pub destination: &'a AccountInfo,
+    pub authority: &'a AccountInfo,
+    pub token_program: &'a Pubkey,
+}
+
+impl CloseAccount<'_> {
+    pub fn invoke_signed(&self, signers: &[Signer]) -> ProgramResult {
+        let account_metas = [
+            AccountMeta::writable(self.account.key()),
+            AccountMeta::writable(self.destination.key()),
+            AccountMeta::readonly_signer(self.authority.key()),
+        ];
+
+        let instruction = Instruction {
+            program_id: self.token_program,
+            accounts: &account_metas,
+            data: &[9],
+        };
+
+        invoke_signed(
+            &instruction,
+            &[self.account, self.destination, self.authority],
+            signers,
+        )
+    }
+}
+
+/// Closes `vault` into `destination`. Token-2022 won't close an account still
+/// holding withheld transfer fees, so those are harvested to the mint first.
+pub fn close_vault(
+    vault: &AccountInfo,
+    mint: &AccountInfo,
+    destination: &AccountInfo,
+    authority: &AccountInfo,
+    token_program: &Pubkey,
+    signers: &[Signer],
+) -> ProgramResult {
+    if token_account_withheld(vault)? != 0 {
+        mint.check_writable()?;
+        HarvestWithheldTokensToMint {
+            mint,
+            account: vault,
+        }
+        .invoke()?;
+    }
+
+    CloseAccount {
+        account: vault,
+        destination,
+        authority,
+        token_program,
+    }
+    .invoke_signed(signers)
+}
+
+// Token-2022 extensions start after the padded base account and the account type byte.
+const EXTENSIONS_OFFSET: usize = TOKEN_ACCOUNT_BASE_LEN + 1;
+
+const EXTENSION_TRANSFER_FEE_CONFIG: u16 = 1;
+const EXTENSION_TRANSFER_FEE_AMOUNT: u16 = 2;
+const EXTENSION_NON_TRANSFERABLE: u16 = 9;
+const EXTENSION_PERMANENT_DELEGATE: u16 = 12;
+const EXTENSION_TRANSFER_HOOK: u16 = 14;
+const EXTENSION_PAUSABLE: u16 = 26;
+
+const TRANSFER_FEE_CONFIG_LEN: usize = 108;
+// older and newer fees follow both authorities and the withheld amount
+const OLDER_TRANSFER_FEE_OFFSET: usize = 72;
+const NEWER_TRANSFER_FEE_OFFSET: usize = 90;
+
+const ONE_IN_BASIS_POINTS: u128 = 10_000;
+
+/// A Token-2022 transfer fee, as configured for one epoch range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransferFee {
+    pub epoch: u64,
+    pub maximum_fee: u64,
+    pub basis_points: u16,
+}
+
+impl TransferFee {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            epoch: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            maximum_fee: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            basis_points: u16::from_le_bytes(bytes[16..18].try_into().unwrap()),
+        }
+    }
+
+    /// Fee withheld when sending `amount`, rounded up like Token-2022 does.
+    #[cfg(all(test, feature = "std"))]
+    pub fn fee(&self, amount: u64) -> Option<u64> {
+        if self.basis_points == 0 || amount == 0 {
+            return Some(0);
+        }
+        let numerator = (amount as u128).checked_mul(self.basis_points as u128)?;
+        let fee = numerator.div_ceil(ONE_IN_BASIS_POINTS);
+        u64::try_from(fee).ok().map(|fee| fee.min(self.maximum_fee))
+    }
+
+    /// Amount to send so that exactly `post_fee_amount` lands in the destination.
+    pub fn pre_fee_amount(&self, post_fee_amount: u64) -> Option<u64> {
+        let basis_points = self.basis_points as u128;
+        match (basis_points, post_fee_amount) {
+            (0, _) => Some(post_fee_amount),
+            (_, 0) => Some(0),
+            (ONE_IN_BASIS_POINTS, _) => post_fee_amount.checked_add(self.maximum_fee),
+            _ => {
+                let numerator = (post_fee_amount as u128).checked_mul(ONE_IN_BASIS_POINTS)?;
+                let denominator = ONE_IN_BASIS_POINTS.checked_sub(basis_points)?;
+                let raw_pre_fee_amount = numerator.div_ceil(denominator);
+                if raw_pre_fee_amount - post_fee_amount as u128 >= self.maximum_fee as u128 {
+                    post_fee_amount.checked_add(self.maximum_fee)
+                } else {
+                    u64::try_from(raw_pre_fee_amount).ok()
+                }
+            }
+        }
+    }
+}
+
+/// Validates the mint's Token-2022 extensions and returns the transfer fee in
+/// effect at `epoch`, if any.
+///
+/// Mints the escrow cannot hold safely (non-transferable, permanent delegate,
+/// transfer hook, pausable) are rejected.
+pub fn mint_transfer_fee(
+    mint: &AccountInfo,
+    epoch: u64,
+) -> Result<Option<TransferFee>, ProgramError> {
+    if token_program_of(mint)? == &TOKEN_PROGRAM_ID {
+        return Ok(None);
+    }
+
+    let data = mint.try_borrow_data()?;
+    if data.len() < MINT_BASE_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if data.len() <= EXTENSIONS_OFFSET {
+        return Ok(None);
+    }
+
+    let mut transfer_fee = None;
+    let mut offset = EXTENSIONS_OFFSET;
+    while offset + 4 <= data.len() {
+        let extension = u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let len = u16::from_le_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        let value = data
+            .get(offset + 4..offset + 4 + len)
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        match extension {
+            // the rest of the buffer is unused
+            0 => break,
+            EXTENSION_TRANSFER_FEE_CONFIG => {
+                if len != TRANSFER_FEE_CONFIG_LEN {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                let older = TransferFee::from_bytes(&value[OLDER_TRANSFER_FEE_OFFSET..]);
+                let newer = TransferFee::from_bytes(&value[NEWER_TRANSFER_FEE_OFFSET..]);
+                transfer_fee = Some(if epoch >= newer.epoch { newer } else { older });
+            }
+            EXTENSION_NON_TRANSFERABLE => return Err(MyProgramError::NonTransferableMint.into()),
+            EXTENSION_PERMANENT_DELEGATE => {
+                return Err(MyProgramError::PermanentDelegateMint.into())
+            }
+            EXTENSION_TRANSFER_HOOK | EXTENSION_PAUSABLE => {
+                return Err(MyProgramError::UnsupportedMintExtension.into())
+            }
+            _ => {}
+        }
+        offset += 4 + len;
+    }
+
+    Ok(transfer_fee)
+}
+
+/// Transfer fees withheld in a Token-2022 account, zero for accounts without
+/// the TransferFeeAmount extension.
+pub fn token_account_withheld(account: &AccountInfo) -> Result<u64, ProgramError> {
+    if token_program_of(account)? == &TOKEN_PROGRAM_ID {
+        return Ok(0);
+    }
+
+    let data = account.try_borrow_data()?;
+    if data.len() < TOKEN_ACCOUNT_BASE_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut offset = EXTENSIONS_OFFSET;
+    while offset + 4 <= data.len() {
+        let extension = u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let len = u16::from_le_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        let value = data
+            .get(offset + 4..offset + 4 + len)
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        match extension {
+            0 => break,
+            EXTENSION_TRANSFER_FEE_AMOUNT => {
+                return value
+                    .try_into()
+                    .map(u64::from_le_bytes)
+                    .map_err(|_| ProgramError::InvalidAccountData)
+            }
+            _ => {}
+        }
+        offset += 4 + len;
+    }
+
+    Ok(0)
+}
@@ -0,0 +1,84 @@
+use pinocchio::{
+    account_info::AccountInfo,
+    program_error::ProgramError,
+    pubkey::{create_program_address, find_program_address, Pubkey},
+    ProgramResult,
+};
+
+use crate::{error::MyProgramError, instruction::delegate::DELEGATION_ACCOUNT};
+
+/// Checks shared by every instruction handler, run right after destructuring
+/// the accounts slice.
+pub trait AccountCheck {
+    fn check_signer(&self) -> ProgramResult;
+
+    fn check_writable(&self) -> ProgramResult;
+
+    fn check_owner(&self, owner: &Pubkey) -> ProgramResult;
+
+    /// Checks the account is the executable program `program_id`.
+    fn check_program(&self, program_id: &Pubkey) -> ProgramResult;
+
+    /// Checks the account is the PDA of `seeds`, bump included, under this
+    /// program. A cheaper alternative to [`AccountCheck::find_pda`] when the
+    /// bump is already stored.
+    fn check_pda(&self, seeds: &[&[u8]], mismatch: MyProgramError) -> ProgramResult;
+
+    /// Checks the account is the canonical PDA of `seeds` under this program
+    /// and returns its bump.
+    fn find_pda(&self, seeds: &[&[u8]], mismatch: MyProgramError) -> Result<u8, ProgramError>;
+}
+
+impl AccountCheck for AccountInfo {
+    fn check_signer(&self) -> ProgramResult {
+        if !self.is_signer() {
+            return Err(MyProgramError::NotSigner.into());
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> ProgramResult {
+        if !self.is_writable() {
+            return Err(MyProgramError::NotWritable.into());
+        }
+        Ok(())
+    }
+
+    fn check_owner(&self, owner: &Pubkey) -> ProgramResult {
+        if unsafe { self.owner() } != owner {
+            return Err(MyProgramError::InvalidOwner.into());
+        }
+        Ok(())
+    }
+
+    fn check_program(&self, program_id: &Pubkey) -> ProgramResult {
+        if self.key() != program_id || !self.executable() {
+            return Err(MyProgramError::InvalidProgram.into());
+        }
+        Ok(())
+    }
+
+    fn check_pda(&self, seeds: &[&[u8]], mismatch: MyProgramError) -> ProgramResult {
+        match create_program_address(seeds, &crate::ID) {
+            Ok(pda) if pda == *self.key() => Ok(()),
+            _ => Err(mismatch.into()),
+        }
+    }
+
+    fn find_pda(&self, seeds: &[&[u8]], mismatch: MyProgramError) -> Result<u8, ProgramError> {
+        let (pda, bump) = find_program_address(seeds, &crate::ID);
+        if pda != *self.key() {
+            return Err(mismatch.into());
+        }
+        Ok(bump)
+    }
+}
+
+/// Checks the escrow is held by this program, the rollup owns its state while
+/// it is delegated.
+pub fn check_escrow_owner(escrow: &AccountInfo) -> ProgramResult {
+    if unsafe { escrow.owner() } == &DELEGATION_ACCOUNT {
+        return Err(MyProgramError::EscrowDelegated.into());
+    }
+    escrow.check_owner(&crate::ID)
+}